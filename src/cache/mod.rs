@@ -0,0 +1,252 @@
+use crate::config::duration;
+use crate::opt::CacheArgs;
+use ansi_term::Color;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static XDG_CACHE_HOME_ENV_KEY: &str = "XDG_CACHE_HOME";
+static CACHE_DIR: &str = "zabbrev";
+static ENTRY_EXTENSION: &str = "yaml";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub name: String,
+    pub created: u64,
+    #[serde(with = "duration")]
+    pub ttl: Duration,
+    pub value: String,
+}
+
+impl Entry {
+    /// Returns the time left until the entry expires, or `None` if it has.
+    pub fn remaining(&self, now: u64) -> Option<Duration> {
+        let expires = Duration::from_secs(self.created) + self.ttl;
+        expires
+            .checked_sub(Duration::from_secs(now))
+            .filter(|d| !d.is_zero())
+    }
+}
+
+/// Results of dynamic snippets, stored in a file per entry.
+#[derive(Debug)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// Returns the cache under `${XDG_CACHE_HOME:-$HOME/.cache}/zabbrev`.
+    pub fn open() -> Option<Self> {
+        let cache_home = match std::env::var_os(XDG_CACHE_HOME_ENV_KEY) {
+            Some(dir) => PathBuf::from(dir),
+            None => dirs::home_dir()?.join(".cache"),
+        };
+
+        Some(Self::at(cache_home.join(CACHE_DIR)))
+    }
+
+    pub fn at<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Returns the value stored for `key` unless it has expired.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let entry = self.read(&self.entry_path(key))?;
+        entry.remaining(now()).map(|_| entry.value)
+    }
+
+    pub fn set(&self, key: &str, name: &str, ttl: Duration, value: &str) -> io::Result<()> {
+        let entry = Entry {
+            name: name.to_string(),
+            created: now(),
+            ttl,
+            value: value.to_string(),
+        };
+        let content = serde_yaml::to_string(&entry).map_err(io::Error::other)?;
+
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.entry_path(key), content)
+    }
+
+    /// Returns the entries sorted by name, including expired ones.
+    pub fn entries(&self) -> Vec<Entry> {
+        let mut entries = fs::read_dir(&self.dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| self.read(&entry.ok()?.path()))
+            .collect::<Vec<_>>();
+
+        entries.sort_by(|a, b| (&a.name, a.created).cmp(&(&b.name, b.created)));
+        entries
+    }
+
+    /// Removes every entry and returns how many there were.
+    pub fn clear(&self) -> io::Result<usize> {
+        let mut count = 0;
+
+        for entry in fs::read_dir(&self.dir).into_iter().flatten() {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == ENTRY_EXTENSION) {
+                fs::remove_file(path)?;
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", key, ENTRY_EXTENSION))
+    }
+
+    fn read(&self, path: &Path) -> Option<Entry> {
+        if path.extension()? != ENTRY_EXTENSION {
+            return None;
+        }
+
+        serde_yaml::from_str(&fs::read_to_string(path).ok()?).ok()
+    }
+}
+
+/// Returns the key of the result of `source` in the abbrev `name`.
+///
+/// Results differ by the current directory and the environment variables that `source` refers to.
+pub fn cache_key(name: &str, source: &str, cwd: &Path) -> String {
+    let mut hasher = Sha256::new();
+
+    for part in [name, source, &cwd.to_string_lossy()] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+
+    for (key, value) in referenced_env(source) {
+        hasher.update(format!("{}={}", key, value).as_bytes());
+        hasher.update([0]);
+    }
+
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn referenced_env(source: &str) -> Vec<(String, String)> {
+    let pattern =
+        Regex::new(r"\$\{?([A-Za-z_][A-Za-z0-9_]*)|\{\{\s*env\.([A-Za-z_][A-Za-z0-9_]*)").unwrap();
+
+    let mut names = pattern
+        .captures_iter(source)
+        .filter_map(|captures| captures.get(1).or_else(|| captures.get(2)))
+        .map(|name| name.as_str().to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+
+    names
+        .into_iter()
+        .map(|name| {
+            let value = std::env::var(&name).unwrap_or_default();
+            (name, value)
+        })
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+pub fn run(args: &CacheArgs) {
+    let cache = Cache::open().expect("could not determine cache directory");
+
+    if let (Some(key), Some(ttl)) = (&args.store, args.ttl) {
+        let name = args.name.as_deref().unwrap_or("");
+        let value = args.value.as_deref().unwrap_or("");
+
+        if let Err(err) = cache.set(key, name, ttl, value) {
+            let error_message = format!("failed to store cache: {}", err);
+            let error_style = Color::Red.normal();
+
+            eprintln!("{}", error_style.paint(error_message));
+        }
+        return;
+    }
+
+    if args.clear {
+        match cache.clear() {
+            Ok(count) => println!("removed {} entries", count),
+            Err(err) => {
+                let error_message = format!("failed to clear cache: {}", err);
+                let error_style = Color::Red.normal();
+
+                eprintln!("{}", error_style.paint(error_message));
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let now = now();
+    for entry in cache.entries() {
+        let remaining = match entry.remaining(now) {
+            Some(remaining) => format!("{}s left", remaining.as_secs()),
+            None => "expired".to_string(),
+        };
+
+        println!("{}\t{}\t{}", entry.name, remaining, entry.value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_cache() {
+        let dir = TempDir::new("cache");
+        let cache = Cache::at(&dir);
+        let key = cache_key("context", "kubectl config current-context", Path::new("/"));
+
+        assert_eq!(cache.get(&key), None);
+
+        cache
+            .set(&key, "context", Duration::from_secs(30), "dev")
+            .unwrap();
+        cache
+            .set("expired", "old", Duration::from_secs(0), "x")
+            .unwrap();
+
+        assert_eq!(cache.get(&key), Some("dev".to_string()));
+        assert_eq!(cache.get("expired"), None);
+        assert_eq!(
+            cache.entries().iter().map(|e| &e.name).collect::<Vec<_>>(),
+            vec!["context", "old"]
+        );
+        assert_eq!(cache.clear().unwrap(), 2);
+        assert!(cache.entries().is_empty());
+    }
+
+    #[test]
+    fn test_cache_key() {
+        std::env::set_var("ZABBREV_TEST_CONTEXT", "a");
+        let a = cache_key("name", "echo $ZABBREV_TEST_CONTEXT", Path::new("/"));
+        std::env::set_var("ZABBREV_TEST_CONTEXT", "b");
+        let b = cache_key("name", "echo $ZABBREV_TEST_CONTEXT", Path::new("/"));
+
+        assert_ne!(a, b);
+        assert_ne!(
+            b,
+            cache_key("name", "echo $ZABBREV_TEST_CONTEXT", Path::new("/tmp"))
+        );
+        assert_eq!(b.len(), 64);
+    }
+}
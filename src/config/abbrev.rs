@@ -1,6 +1,11 @@
+use super::duration;
+use crate::expand::script;
 use ansi_term::Color;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Trigger {
@@ -10,23 +15,55 @@ pub enum Trigger {
     AbbrRegex(String),
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
 pub enum Operation {
+    #[default]
     #[serde(rename = "replace-self")]
     ReplaceSelf,
     #[serde(rename = "replace-command")]
     ReplaceCommand,
     #[serde(rename = "replace-all")]
     ReplaceAll,
+    #[serde(rename = "replace-buffer")]
+    ReplaceBuffer,
     #[serde(rename = "append")]
     Append,
     #[serde(rename = "prepend")]
     Prepend,
+    #[serde(rename = "wrap")]
+    Wrap,
+    #[serde(rename = "substitute")]
+    Substitute,
 }
-impl Default for Operation {
-    fn default() -> Self {
-        Operation::ReplaceSelf
-    }
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum Quote {
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "shell")]
+    Shell,
+    #[serde(rename = "single")]
+    Single,
+    #[serde(rename = "double")]
+    Double,
+    #[serde(rename = "per-line")]
+    PerLine,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum Action {
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "accept-line")]
+    AcceptLine,
+    #[serde(rename = "push-line")]
+    PushLine,
+    #[serde(rename = "edit-command-line")]
+    EditCommandLine,
+    #[serde(rename = "clear-screen")]
+    ClearScreen,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -42,16 +79,86 @@ pub struct Abbrev {
     #[serde(flatten)]
     pub trigger: Trigger,
 
+    #[serde(default)]
     pub snippet: String,
 
+    #[serde(rename = "snippet-file")]
+    pub snippet_file: Option<PathBuf>,
+
+    #[serde(rename = "snippet-command")]
+    pub snippet_command: Option<Vec<String>>,
+
+    #[serde(default, with = "duration::option")]
+    pub timeout: Option<Duration>,
+
+    #[serde(default, with = "duration::option")]
+    pub cache: Option<Duration>,
+
+    pub script: Option<String>,
+
     #[serde(default)]
     pub operation: Operation,
 
+    #[serde(default)]
+    pub before: String,
+
+    #[serde(default)]
+    pub after: String,
+
+    #[serde(default = "default_as_false", rename = "keep-trigger")]
+    pub keep_trigger: bool,
+
+    #[serde(default)]
+    pub pattern: String,
+
+    #[serde(default)]
+    pub replacement: String,
+
     #[serde(default = "default_as_false")]
     pub evaluate: bool,
+
+    #[serde(default = "default_as_false")]
+    pub multiline: bool,
+
+    #[serde(default)]
+    pub quote: Quote,
+
+    #[serde(default)]
+    pub action: Action,
+
+    pub recursive: Option<bool>,
+
+    pub cursor: Option<String>,
 }
 
 impl Abbrev {
+    pub fn name(&self) -> &str {
+        self.name.as_ref().unwrap_or(&self.snippet)
+    }
+
+    /// Returns the name, or the trigger if neither the name nor the snippet is given.
+    pub fn label(&self) -> &str {
+        match &self.trigger {
+            _ if !self.name().is_empty() => self.name(),
+            Trigger::Abbr(abbr) => abbr,
+            Trigger::AbbrRegex(regex) => regex,
+        }
+    }
+
+    /// Returns how many of `snippet`, `snippet-file`, `snippet-command` and a script generating
+    /// the snippet are given.
+    pub fn snippet_sources(&self) -> usize {
+        [
+            !self.snippet.is_empty(),
+            self.snippet_file.is_some(),
+            self.snippet_command.is_some(),
+            script::defines_snippet(self),
+        ]
+        .iter()
+        .filter(|&&given| given)
+        .count()
+    }
+
     pub fn is_match(
         &self,
         _command: &str,
@@ -59,7 +166,7 @@ impl Abbrev {
         last_arg: &str,
         is_no_internal_args: bool,
     ) -> bool {
-        if !(self.context == "" && self.global) {
+        if !(self.context.is_empty() && self.global) {
             if !self.global && !is_no_internal_args {
                 return false;
             }
@@ -75,8 +182,8 @@ impl Abbrev {
                 match pattern_or_error {
                     Ok(pattern) => pattern.is_match(last_arg),
                     Err(err) => {
-                        let name = self.name.as_ref().unwrap_or(&self.snippet);
-                        let error_message = format!("invalid regex in abbrev '{}': {}", name, err);
+                        let error_message =
+                            format!("invalid regex in abbrev '{}': {}", self.name(), err);
                         let error_style = Color::Red.normal();
 
                         eprintln!("{}", error_style.paint(error_message));
@@ -103,12 +210,59 @@ impl Abbrev {
         //             }
         //         }
     }
+
+    /// Returns the capture groups of `abbr-regex` in `last_arg`, or nothing for `abbr`.
+    pub fn captures<'a>(&self, last_arg: &'a str) -> Vec<&'a str> {
+        let regex = match &self.trigger {
+            Trigger::AbbrRegex(regex) => regex,
+            Trigger::Abbr(_) => return Vec::new(),
+        };
+
+        Regex::new(regex)
+            .ok()
+            .and_then(|pattern| pattern.captures(last_arg))
+            .map(|captures| {
+                captures
+                    .iter()
+                    .skip(1)
+                    .map(|group| group.map_or("", |m| m.as_str()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Replaces the first match of `pattern` in `command` with `replacement`.
+    ///
+    /// Returns `None` if `pattern` does not match or is invalid.
+    pub fn substitute<'a>(&self, command: &'a str) -> Option<Cow<'a, str>> {
+        match Regex::new(&self.pattern) {
+            Ok(pattern) if pattern.is_match(command) => {
+                Some(pattern.replace(command, self.replacement.as_str()))
+            }
+            Ok(_) => None,
+            Err(err) => {
+                let error_message = format!("invalid pattern in abbrev '{}': {}", self.name(), err);
+                let error_style = Color::Red.normal();
+
+                eprintln!("{}", error_style.paint(error_message));
+                None
+            }
+        }
+    }
+}
+
+fn default_as_false() -> bool {
+    false
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn abbrev(yaml: &str) -> Abbrev {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
     #[test]
     fn test_is_match() {
         struct Scenario {
@@ -121,267 +275,115 @@ mod tests {
         let scenarios = &[
             Scenario {
                 testname: "should match non-global if first arg",
-                abbr: Abbrev {
-                    name: None,
-                    context: String::new(),
-                    global: false,
-                    trigger: Trigger::Abbr("test".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ abbr: 'test' }"),
                 command: "test",
                 expected: true,
             },
             Scenario {
                 testname: "should not match non-global if second arg",
-                abbr: Abbrev {
-                    name: None,
-                    context: String::new(),
-                    global: false,
-                    trigger: Trigger::Abbr("test".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ abbr: 'test' }"),
                 command: "echo test",
                 expected: false,
             },
             Scenario {
                 testname: "should match global if first arg",
-                abbr: Abbrev {
-                    name: None,
-                    context: String::new(),
-                    global: true,
-                    trigger: Trigger::Abbr("test".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ global: true, abbr: 'test' }"),
                 command: "test",
                 expected: true,
             },
             Scenario {
                 testname: "should match global if second arg",
-                abbr: Abbrev {
-                    name: None,
-                    context: String::new(),
-                    global: true,
-                    trigger: Trigger::Abbr("test".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ global: true, abbr: 'test' }"),
                 command: "echo test",
                 expected: true,
             },
             Scenario {
                 testname: "should match global if third arg",
-                abbr: Abbrev {
-                    name: None,
-                    context: String::new(),
-                    global: true,
-                    trigger: Trigger::Abbr("test".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ global: true, abbr: 'test' }"),
                 command: "echo a test",
                 expected: true,
             },
             Scenario {
                 testname: "should not match non-global with context if first arg",
-                abbr: Abbrev {
-                    name: None,
-                    context: "test".to_string(),
-                    global: false,
-                    trigger: Trigger::Abbr("test".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ context: 'test', abbr: 'test' }"),
                 command: "test",
                 expected: false,
             },
             Scenario {
                 testname: "should match non-global with context if second arg",
-                abbr: Abbrev {
-                    name: None,
-                    context: "echo".to_string(),
-                    global: false,
-                    trigger: Trigger::Abbr("test".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ context: 'echo', abbr: 'test' }"),
                 command: "echo test",
                 expected: true,
             },
             Scenario {
                 testname: "should not match non-global with context if third arg",
-                abbr: Abbrev {
-                    name: None,
-                    context: "echo".to_string(),
-                    global: false,
-                    trigger: Trigger::Abbr("test".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ context: 'echo', abbr: 'test' }"),
                 command: "echo a test",
                 expected: false,
             },
             Scenario {
                 testname: "should not match non-global with context mismatch",
-                abbr: Abbrev {
-                    name: None,
-                    context: "printf".to_string(),
-                    global: false,
-                    trigger: Trigger::Abbr("test".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ context: 'printf', abbr: 'test' }"),
                 command: "echo test",
                 expected: false,
             },
             Scenario {
                 testname: "should not match if context is invalid",
-                abbr: Abbrev {
-                    name: None,
-                    context: "(echo".to_string(),
-                    global: true,
-                    trigger: Trigger::Abbr("test".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ context: '(echo', global: true, abbr: 'test' }"),
                 command: "echo test",
                 expected: false,
             },
             Scenario {
                 testname: "should not match global with context if first arg",
-                abbr: Abbrev {
-                    name: None,
-                    context: "test".to_string(),
-                    global: true,
-                    trigger: Trigger::Abbr("test".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ context: 'test', global: true, abbr: 'test' }"),
                 command: "test",
                 expected: false,
             },
             Scenario {
                 testname: "should match global with context if second arg",
-                abbr: Abbrev {
-                    name: None,
-                    context: "echo".to_string(),
-                    global: true,
-                    trigger: Trigger::Abbr("test".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ context: 'echo', global: true, abbr: 'test' }"),
                 command: "echo test",
                 expected: true,
             },
             Scenario {
                 testname: "should match global with context if third arg",
-                abbr: Abbrev {
-                    name: None,
-                    context: "echo".to_string(),
-                    global: true,
-                    trigger: Trigger::Abbr("test".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ context: 'echo', global: true, abbr: 'test' }"),
                 command: "echo a test",
                 expected: true,
             },
             Scenario {
                 testname: "should match regex pattern if first arg",
-                abbr: Abbrev {
-                    name: None,
-                    context: "".to_string(),
-                    global: false,
-                    trigger: Trigger::AbbrRegex(".+".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ abbr-regex: '.+' }"),
                 command: "test",
                 expected: true,
             },
             Scenario {
                 testname: "should not match regex pattern if second arg",
-                abbr: Abbrev {
-                    name: None,
-                    context: "".to_string(),
-                    global: false,
-                    trigger: Trigger::AbbrRegex(".+".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ abbr-regex: '.+' }"),
                 command: "echo test",
                 expected: false,
             },
             Scenario {
                 testname: "should not match regex pattern if third arg",
-                abbr: Abbrev {
-                    name: None,
-                    context: "".to_string(),
-                    global: false,
-                    trigger: Trigger::AbbrRegex(".+".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ abbr-regex: '.+' }"),
                 command: "echo a test",
                 expected: false,
             },
             Scenario {
                 testname: "should not match regex pattern with context if first arg",
-                abbr: Abbrev {
-                    name: None,
-                    context: "test".to_string(),
-                    global: false,
-                    trigger: Trigger::AbbrRegex(".+".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ context: 'test', abbr-regex: '.+' }"),
                 command: "test",
                 expected: false,
             },
             Scenario {
                 testname: "should match regex pattern with context if second arg",
-                abbr: Abbrev {
-                    name: None,
-                    context: "echo".to_string(),
-                    global: false,
-                    trigger: Trigger::AbbrRegex(".+".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ context: 'echo', abbr-regex: '.+' }"),
                 command: "echo test",
                 expected: true,
             },
             Scenario {
                 testname: "should not match regex pattern with context if third arg",
-                abbr: Abbrev {
-                    name: None,
-                    context: "echo".to_string(),
-                    global: false,
-                    trigger: Trigger::AbbrRegex(".+".to_string()),
-                    snippet: String::new(),
-                    operation: Operation::ReplaceSelf,
-                    evaluate: false,
-                },
+                abbr: abbrev("{ context: 'echo', abbr-regex: '.+' }"),
                 command: "echo a test",
                 expected: false,
             },
@@ -411,8 +413,24 @@ mod tests {
             );
         }
     }
-}
 
-fn default_as_false() -> bool {
-    false
+    #[test]
+    fn test_captures() {
+        let abbrev: Abbrev = serde_yaml::from_str(
+            r"
+            name: clone
+            abbr-regex: '^gh:([^/]+)/(.+)?$'
+            snippet: 'git clone https://github.com/$zabbrev_match[1]/$zabbrev_match[2]'
+            evaluate: true
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            abbrev.captures("gh:a-happin/zabbrev"),
+            vec!["a-happin", "zabbrev"]
+        );
+        assert_eq!(abbrev.captures("gh:a-happin/"), vec!["a-happin", ""]);
+        assert!(abbrev.captures("zabbrev").is_empty());
+    }
 }
@@ -1,16 +1,19 @@
 use std::env;
 use std::ffi::OsString;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 static ZABBREV_CONFIG_FILE_ENV_KEY: &str = "ZABBREV_CONFIG_FILE";
 static XDG_CONFIG_HOME_ENV_KEY: &str = "XDG_CONFIG_HOME";
 
 static DEFAULT_CONFIG_DIR: &str = "zsh";
 static DEFAULT_CONFIG_FILE: &str = "zabbrev.yaml";
+static CONF_D_DIR: &str = "zabbrev.d";
+static PROJECT_CONFIG_FILE: &str = ".zabbrev.yaml";
 
 trait ConfigPath {
     fn env(&self, key: &str) -> Option<OsString>;
     fn home(&self) -> Option<PathBuf>;
+    fn is_file(&self, path: &Path) -> bool;
 }
 
 #[derive(Debug)]
@@ -23,6 +26,9 @@ impl ConfigPath for ConfigPathImpl {
     fn home(&self) -> Option<PathBuf> {
         dirs::home_dir()
     }
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
 }
 
 fn get_default_path<C: ConfigPath>(c: &C) -> Option<PathBuf> {
@@ -31,8 +37,22 @@ fn get_default_path<C: ConfigPath>(c: &C) -> Option<PathBuf> {
         return Some(zabbrev_config_file);
     }
 
+    // Return ${XDG_CONFIG_HOME:-$HOME/.config}/zsh/zabbrev.yaml
+    let mut config_path = get_config_dir(c)?;
+    config_path.push(DEFAULT_CONFIG_FILE);
+    Some(config_path)
+}
+
+fn get_conf_d_path<C: ConfigPath>(c: &C) -> Option<PathBuf> {
+    // Return ${XDG_CONFIG_HOME:-$HOME/.config}/zsh/zabbrev.d, even with $ZABBREV_CONFIG_FILE
+    let mut conf_d_path = get_config_dir(c)?;
+    conf_d_path.push(CONF_D_DIR);
+    Some(conf_d_path)
+}
+
+fn get_config_dir<C: ConfigPath>(c: &C) -> Option<PathBuf> {
     // Get ${XDG_CONFIG_HOME:-$HOME/.config}
-    let config_home =
+    let mut config_dir =
         if let Some(xdg_config_home) = c.env(XDG_CONFIG_HOME_ENV_KEY).map(PathBuf::from) {
             xdg_config_home
         } else {
@@ -41,17 +61,45 @@ fn get_default_path<C: ConfigPath>(c: &C) -> Option<PathBuf> {
             path
         };
 
-    // Return $config_path/zsh/zabbrev.yaml
-    let mut config_path = config_home;
-    config_path.push(DEFAULT_CONFIG_DIR);
-    config_path.push(DEFAULT_CONFIG_FILE);
-    Some(config_path)
+    config_dir.push(DEFAULT_CONFIG_DIR);
+    Some(config_dir)
+}
+
+fn get_project_paths<C: ConfigPath>(c: &C, cwd: &Path) -> Vec<PathBuf> {
+    let home = c.home();
+    let mut paths = Vec::new();
+
+    // Search from cwd up to $HOME, or to the root outside of $HOME
+    for dir in cwd.ancestors() {
+        let path = dir.join(PROJECT_CONFIG_FILE);
+        if c.is_file(&path) {
+            paths.push(path);
+        }
+        if home.as_deref() == Some(dir) {
+            break;
+        }
+    }
+
+    // The farthest comes first so that nearer ones are layered over it
+    paths.reverse();
+    paths
 }
 
 pub fn default_config_path() -> Option<PathBuf> {
     get_default_path(&ConfigPathImpl {})
 }
 
+/// Returns the `zabbrev.d` directory, whose files are loaded after the user config.
+pub fn conf_d_path() -> Option<PathBuf> {
+    get_conf_d_path(&ConfigPathImpl {})
+}
+
+/// Returns the `.zabbrev.yaml` files in `cwd` and its parents, the farthest first.
+pub fn project_config_paths(cwd: &Path) -> Vec<PathBuf> {
+    let cwd = cwd.canonicalize().unwrap_or_else(|_| cwd.to_path_buf());
+    get_project_paths(&ConfigPathImpl {}, &cwd)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -61,6 +109,7 @@ mod tests {
     struct DummyConfigPath {
         envs: HashMap<&'static str, &'static str>,
         home: &'static str,
+        files: Vec<&'static str>,
     }
 
     impl ConfigPath for DummyConfigPath {
@@ -70,6 +119,9 @@ mod tests {
         fn home(&self) -> Option<PathBuf> {
             Some(PathBuf::from(self.home))
         }
+        fn is_file(&self, path: &Path) -> bool {
+            self.files.iter().any(|file| Path::new(file) == path)
+        }
     }
 
     #[test]
@@ -113,6 +165,7 @@ mod tests {
             let c = DummyConfigPath {
                 envs: s.envs.clone(),
                 home: s.home,
+                files: Vec::new(),
             };
 
             let expected = Some(PathBuf::from(s.expected));
@@ -120,4 +173,73 @@ mod tests {
             assert_eq!(get_default_path(&c), expected, "{}", s.testname);
         }
     }
+
+    #[test]
+    fn test_conf_d_path() {
+        let c = DummyConfigPath {
+            envs: vec![
+                ("ZABBREV_CONFIG_FILE", "/home/user/.zabrze.yaml"),
+                ("XDG_CONFIG_HOME", "/home/user/.xdgConfig"),
+            ]
+            .into_iter()
+            .collect(),
+            home: "/home/user/",
+            files: Vec::new(),
+        };
+
+        assert_eq!(
+            get_conf_d_path(&c),
+            Some(PathBuf::from("/home/user/.xdgConfig/zsh/zabbrev.d"))
+        );
+    }
+
+    #[test]
+    fn test_project_paths() {
+        struct Scenario {
+            pub testname: &'static str,
+            pub cwd: &'static str,
+            pub expected: Vec<&'static str>,
+        }
+
+        let c = DummyConfigPath {
+            envs: HashMap::new(),
+            home: "/home/user",
+            files: vec![
+                "/.zabbrev.yaml",
+                "/home/.zabbrev.yaml",
+                "/home/user/.zabbrev.yaml",
+                "/home/user/repo/.zabbrev.yaml",
+                "/home/user/repo/crates/.zabbrev.yaml",
+                "/srv/.zabbrev.yaml",
+            ],
+        };
+
+        let scenarios = [
+            Scenario {
+                testname: "stop at HOME",
+                cwd: "/home/user/repo/crates/cli",
+                expected: vec![
+                    "/home/user/.zabbrev.yaml",
+                    "/home/user/repo/.zabbrev.yaml",
+                    "/home/user/repo/crates/.zabbrev.yaml",
+                ],
+            },
+            Scenario {
+                testname: "stop at the root outside of HOME",
+                cwd: "/srv/app",
+                expected: vec!["/.zabbrev.yaml", "/srv/.zabbrev.yaml"],
+            },
+        ];
+
+        for s in &scenarios {
+            let expected = s.expected.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+            assert_eq!(
+                get_project_paths(&c, Path::new(s.cwd)),
+                expected,
+                "{}",
+                s.testname
+            );
+        }
+    }
 }
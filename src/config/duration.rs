@@ -0,0 +1,70 @@
+//! (De)serializes durations written like `500ms`, `30s`, `5m` or `1h`.
+
+use serde::{de, Deserialize, Deserializer, Serializer};
+use std::time::Duration;
+
+pub fn parse(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let unit_index = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(unit_index);
+
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration `{}'", s))?;
+
+    match unit.trim() {
+        "ms" => Ok(Duration::from_millis(number)),
+        "" | "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        "h" => Ok(Duration::from_secs(number * 60 * 60)),
+        unit => Err(format!("invalid duration unit `{}' in `{}'", unit, s)),
+    }
+}
+
+pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{}ms", duration.as_millis()))
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawDuration {
+    Seconds(u64),
+    Text(String),
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    match RawDuration::deserialize(deserializer)? {
+        RawDuration::Seconds(seconds) => Ok(Duration::from_secs(seconds)),
+        RawDuration::Text(s) => parse(&s).map_err(de::Error::custom),
+    }
+}
+
+pub mod option {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => super::serialize(duration, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        super::deserialize(deserializer).map(Some)
+    }
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(parse("500ms"), Ok(Duration::from_millis(500)));
+    assert_eq!(parse("30s"), Ok(Duration::from_secs(30)));
+    assert_eq!(parse("2"), Ok(Duration::from_secs(2)));
+    assert_eq!(parse("5m"), Ok(Duration::from_secs(300)));
+    assert!(parse("1d").is_err());
+    assert!(parse("s").is_err());
+}
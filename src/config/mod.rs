@@ -1,14 +1,20 @@
 pub mod abbrev;
 pub mod config_path;
+pub mod duration;
 
 pub use abbrev::Abbrev;
-pub use config_path::default_config_path;
+pub use config_path::{conf_d_path, default_config_path, project_config_paths};
 
+use crate::trust::Trust;
+use abbrev::Operation;
 use ansi_term::Color;
 use serde::{Deserialize, Serialize};
-use std::fs::File;
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::fs::{self, File};
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -18,36 +24,476 @@ pub enum ConfigError {
 
     #[error(transparent)]
     YamlError(#[from] serde_yaml::Error),
+
+    #[error("could not read snippet file `{1}' of abbrev '{0}': {2}")]
+    SnippetFile(String, String, io::Error),
+
+    #[error("`{1}' included from `{0}': {2}")]
+    Include(String, String, Box<ConfigError>),
+
+    #[error("invalid include pattern `{1}' in `{0}': {2}")]
+    IncludePattern(String, String, glob::PatternError),
+
+    #[error("include cycle detected: {0}")]
+    IncludeCycle(String),
+
+    #[error("abbrev '{0}' needs exactly one of snippet, snippet-file, snippet-command or a script generating it")]
+    SnippetSource(String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
+    #[serde(default)]
+    pub recursive: bool,
+
+    #[serde(default = "default_cursor")]
+    pub cursor: String,
+
+    #[serde(default = "default_timeout", with = "duration")]
+    pub timeout: Duration,
+
+    #[serde(default)]
+    pub include: Vec<String>,
+
+    #[serde(default)]
+    pub disable: Vec<String>,
+
+    #[serde(default)]
     pub abbrevs: Vec<Abbrev>,
+
+    /// Every file read to load this config, including included files and snippet files
+    #[serde(skip)]
+    pub files: Vec<PathBuf>,
+}
+
+fn default_cursor() -> String {
+    "{{}}".to_string()
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(1)
 }
 
 impl Config {
     #[allow(dead_code)]
     pub fn load_from_str(s: &str) -> Result<Self, ConfigError> {
-        let config = serde_yaml::from_str(s)?;
+        let config: Self = serde_yaml::from_str(s)?;
+        config.validate()?;
         Ok(config)
     }
 
+    /// Loads `path` with the files it includes.
+    ///
+    /// Abbrevs of included files follow those of the including file. Other settings are only
+    /// read from `path`.
     pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        Self::load_with_includes(path.as_ref(), &mut Vec::new())
+    }
+
+    /// Appends the abbrevs of every `*.yaml` in `dir`, such as `zabbrev.d`, in lexical order.
+    ///
+    /// A missing directory adds nothing.
+    pub fn extend_from_dir(&mut self, dir: &Path) -> Result<(), ConfigError> {
+        let mut files = fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|file| file.extension().is_some_and(|ext| ext == "yaml"))
+            .collect::<Vec<_>>();
+        files.sort();
+
+        for file in files {
+            let included =
+                Self::load_from_file(&file).map_err(|err| include_error(dir, &file, err))?;
+            self.abbrevs.extend(included.abbrevs);
+            self.files.extend(included.files);
+        }
+
+        Ok(())
+    }
+
+    /// Loads `path` and the files it includes, which are relative to its directory.
+    ///
+    /// `chain` is the files including `path`, to detect cycles.
+    fn load_with_includes(path: &Path, chain: &mut Vec<PathBuf>) -> Result<Self, ConfigError> {
         let file = File::open(path)?;
-        let config = serde_yaml::from_reader(&file)?;
+        let mut config: Self = serde_yaml::from_reader(&file)?;
+        let dir = parent_dir(path);
+
+        config.files.push(path.to_path_buf());
+        config.validate()?;
+        config.read_snippet_files(dir)?;
+        chain.push(canonical_path(path));
+
+        for pattern in std::mem::take(&mut config.include) {
+            for included in expand_include(path, &pattern)? {
+                let canonical = canonical_path(&included);
+                if chain.contains(&canonical) {
+                    let cycle = chain
+                        .iter()
+                        .chain(std::iter::once(&canonical))
+                        .map(|file| file.to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join(" -> ");
+                    return Err(ConfigError::IncludeCycle(cycle));
+                }
+
+                let inner = Self::load_with_includes(&included, chain)
+                    .map_err(|err| include_error(path, &included, err))?;
+                config.abbrevs.extend(inner.abbrevs);
+                config.files.extend(inner.files);
+            }
+        }
+
+        chain.pop();
         Ok(config)
     }
 
-    pub fn load_or_exit() -> Self {
+    /// Checks that every abbrev has a single source of its snippet, except for `wrap` and
+    /// `substitute` which rewrite the typed command instead.
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = self.abbrevs.iter().find(|abbrev| {
+            let rewrites = matches!(abbrev.operation, Operation::Wrap | Operation::Substitute);
+            !rewrites && abbrev.snippet_sources() != 1
+        });
+
+        match invalid {
+            Some(abbrev) => Err(ConfigError::SnippetSource(abbrev.label().to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Reads `snippet-file` of every abbrev into `snippet`, relative to `dir`.
+    fn read_snippet_files(&mut self, dir: &Path) -> Result<(), ConfigError> {
+        for abbrev in &mut self.abbrevs {
+            let snippet_file = match &abbrev.snippet_file {
+                Some(snippet_file) => dir.join(snippet_file),
+                None => continue,
+            };
+
+            let content = fs::read_to_string(&snippet_file).map_err(|err| {
+                ConfigError::SnippetFile(
+                    abbrev.name().to_string(),
+                    snippet_file.to_string_lossy().into_owned(),
+                    err,
+                )
+            })?;
+
+            // Files end with a newline, which is not a part of the snippet
+            abbrev.snippet = content.strip_suffix('\n').unwrap_or(&content).to_string();
+            self.files.push(snippet_file);
+        }
+
+        Ok(())
+    }
+
+    /// Puts the abbrevs of a project config over the ones of this config.
+    ///
+    /// Abbrevs named in `disable` are removed, and abbrevs with the same name as an existing one
+    /// replace it. The others take precedence over the existing ones.
+    pub fn layer(&mut self, project: Config) {
+        self.abbrevs.retain(|abbrev| {
+            !project
+                .disable
+                .iter()
+                .any(|name| abbrev.name.as_ref() == Some(name))
+        });
+
+        let mut added = Vec::new();
+        for abbrev in project.abbrevs {
+            let overridden = abbrev.name.as_ref().and_then(|name| {
+                self.abbrevs
+                    .iter()
+                    .position(|a| a.name.as_ref() == Some(name))
+            });
+
+            match overridden {
+                Some(index) => self.abbrevs[index] = abbrev,
+                None => added.push(abbrev),
+            }
+        }
+
+        added.append(&mut self.abbrevs);
+        self.abbrevs = added;
+    }
+
+    /// Loads the user config and `zabbrev.d`, then layers the trusted project configs found
+    /// from `cwd` over them.
+    pub fn load_or_exit(cwd: &Path) -> Self {
         let path = &default_config_path().expect("could not determine config file path");
 
-        Self::load_from_file(path).unwrap_or_else(|err| {
+        let loaded = Self::load_from_file(path).and_then(|mut config| {
+            if let Some(dir) = conf_d_path() {
+                config.extend_from_dir(&dir)?;
+            }
+            Ok(config)
+        });
+        let mut config = loaded.unwrap_or_else(|err| {
             let path = path.to_string_lossy();
             let error_message = format!("failed to load config `{}': {}", path, err);
             let error_style = Color::Red.normal();
 
             eprintln!("{}", error_style.paint(error_message));
             std::process::exit(1);
-        })
+        });
+
+        let trust = Trust::open();
+
+        // A broken project config is skipped rather than disabling every abbrev
+        for path in project_config_paths(cwd) {
+            // Reading the files is harmless, and tells which ones the trust covers
+            let loaded = Self::load_from_file(&path);
+            let files = match &loaded {
+                Ok(project) => project.files.clone(),
+                Err(_) => vec![path.clone()],
+            };
+
+            // Project configs come with cloned repositories, and may run any command
+            if !trust
+                .as_ref()
+                .is_some_and(|trust| trust.is_trusted(&path, &files))
+            {
+                // Reported once until the files change, as this runs on every expansion
+                if trust
+                    .as_ref()
+                    .is_none_or(|trust| trust.first_warning(&path, &files))
+                {
+                    let path = path.to_string_lossy();
+                    let error_message = format!(
+                        "`{}' is not trusted, run `zabbrev allow {}' to load it",
+                        path,
+                        escape(Cow::from(path.as_ref()))
+                    );
+                    let error_style = Color::Red.normal();
+
+                    eprintln!("{}", error_style.paint(error_message));
+                }
+                continue;
+            }
+
+            match loaded {
+                Ok(project) => config.layer(project),
+                Err(err) => {
+                    let path = path.to_string_lossy();
+                    let error_message = format!("failed to load config `{}': {}", path, err);
+                    let error_style = Color::Red.normal();
+
+                    eprintln!("{}", error_style.paint(error_message));
+                }
+            }
+        }
+
+        config
+    }
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    path.parent().unwrap_or_else(|| Path::new("."))
+}
+
+fn canonical_path(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Returns the files matching `pattern` included by `includer`.
+///
+/// A pattern without wildcards is a file which must exist, while a glob may match nothing.
+fn expand_include(includer: &Path, pattern: &str) -> Result<Vec<PathBuf>, ConfigError> {
+    let path = parent_dir(includer).join(pattern);
+
+    if !pattern.contains(['*', '?', '[']) {
+        return Ok(vec![path]);
+    }
+
+    let paths = glob::glob(&path.to_string_lossy()).map_err(|err| {
+        ConfigError::IncludePattern(
+            includer.to_string_lossy().into_owned(),
+            pattern.to_string(),
+            err,
+        )
+    })?;
+
+    Ok(paths.filter_map(Result::ok).collect())
+}
+
+fn include_error(includer: &Path, included: &Path, err: ConfigError) -> ConfigError {
+    match err {
+        // The cycle already shows every file
+        ConfigError::IncludeCycle(_) => err,
+        err => ConfigError::Include(
+            includer.to_string_lossy().into_owned(),
+            included.to_string_lossy().into_owned(),
+            Box::new(err),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_snippet_file() {
+        let dir = TempDir::new("config");
+        let config_path = dir.join("zabbrev.yaml");
+
+        dir.write(
+            "snippets/docker-run.sh",
+            "docker run --rm -it \\\n  -v \"$PWD:/work\" {{}}\n",
+        );
+        dir.write(
+            "zabbrev.yaml",
+            concat!(
+                "abbrevs:\n",
+                "  - name: docker run\n",
+                "    abbr: dr\n",
+                "    snippet-file: snippets/docker-run.sh\n",
+                "    multiline: true\n",
+            ),
+        );
+
+        let config = Config::load_from_file(&config_path).unwrap();
+        assert_eq!(
+            config.abbrevs[0].snippet,
+            "docker run --rm -it \\\n  -v \"$PWD:/work\" {{}}"
+        );
+
+        fs::remove_file(dir.join("snippets/docker-run.sh")).unwrap();
+        let err = Config::load_from_file(&config_path).unwrap_err();
+        assert!(err.to_string().starts_with(&format!(
+            "could not read snippet file `{}' of abbrev 'docker run': ",
+            dir.join("snippets/docker-run.sh").display()
+        )));
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = TempDir::new("include");
+        let names = |config: &Config| {
+            config
+                .abbrevs
+                .iter()
+                .map(|abbrev| abbrev.name().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        dir.write(
+            "zabbrev.yaml",
+            "include: [git.yaml, 'k8s/*.yaml']\nabbrevs:\n  - { name: root, abbr: r, snippet: r }\n",
+        );
+        dir.write(
+            "git.yaml",
+            "abbrevs:\n  - { name: git, abbr: g, snippet: git }\n",
+        );
+        dir.write(
+            "k8s/b.yaml",
+            "abbrevs:\n  - { name: k8s b, abbr: b, snippet: b }\n",
+        );
+        dir.write(
+            "k8s/a.yaml",
+            "include: [../common/kubectl.yaml]\nabbrevs:\n  - { name: k8s a, abbr: a, snippet: a }\n",
+        );
+        dir.write(
+            "common/kubectl.yaml",
+            "abbrevs:\n  - { name: kubectl, abbr: k, snippet: kubectl }\n",
+        );
+        dir.write(
+            "zabbrev.d/20-team.yaml",
+            "abbrevs:\n  - { name: team, abbr: t, snippet: t }\n",
+        );
+        dir.write(
+            "zabbrev.d/10-user.yaml",
+            "abbrevs:\n  - { name: user, abbr: u, snippet: u }\n",
+        );
+
+        let mut config = Config::load_from_file(dir.join("zabbrev.yaml")).unwrap();
+        config.extend_from_dir(&dir.join("zabbrev.d")).unwrap();
+        assert_eq!(
+            names(&config),
+            vec!["root", "git", "k8s a", "kubectl", "k8s b", "user", "team"]
+        );
+
+        // Missing file
+        fs::remove_file(dir.join("common/kubectl.yaml")).unwrap();
+        let err = Config::load_from_file(dir.join("zabbrev.yaml")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "`{}' included from `{}': `{}' included from `{}': No such file or directory (os error 2)",
+                dir.join("k8s/a.yaml").display(),
+                dir.join("zabbrev.yaml").display(),
+                dir.join("k8s/../common/kubectl.yaml").display(),
+                dir.join("k8s/a.yaml").display(),
+            )
+        );
+
+        // Cycle
+        dir.write("common/kubectl.yaml", "include: [../zabbrev.yaml]\n");
+        let err = Config::load_from_file(dir.join("zabbrev.yaml")).unwrap_err();
+        assert!(matches!(err, ConfigError::IncludeCycle(_)));
+        assert!(err.to_string().ends_with(&format!(
+            "kubectl.yaml -> {}",
+            canonical_path(&dir.join("zabbrev.yaml")).display()
+        )));
+    }
+
+    #[test]
+    fn test_snippet_source() {
+        let load = |abbrev: &str| Config::load_from_str(&format!("abbrevs: [{}]", abbrev));
+
+        assert!(load("{ name: cd, abbr: c, snippet: cd }").is_ok());
+        assert!(load("{ name: wrap, abbr: w, operation: wrap, before: '( ' }").is_ok());
+        assert!(load("{ name: sub, abbr: s, operation: substitute, pattern: a }").is_ok());
+        assert!(load("{ name: date, abbr: d, snippet-command: [date] }").is_ok());
+
+        let err = load("{ name: nosnip, abbr: ns }").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "abbrev 'nosnip' needs exactly one of snippet, snippet-file, snippet-command or a script generating it"
+        );
+
+        let err = load("{ abbr: ns }").unwrap_err();
+        assert!(matches!(err, ConfigError::SnippetSource(name) if name == "ns"));
+
+        let err = load("{ name: both, abbr: b, snippet: date, snippet-command: [date] }");
+        assert!(matches!(err, Err(ConfigError::SnippetSource(_))));
+    }
+
+    #[test]
+    fn test_layer() {
+        let mut config = Config::load_from_str(
+            r"
+            abbrevs:
+              - { name: test, abbr: t, snippet: make test }
+              - { name: git, abbr: g, snippet: git }
+              - { name: ls, abbr: l, snippet: ls -la }
+            ",
+        )
+        .unwrap();
+        let project = Config::load_from_str(
+            r"
+            disable: [ls]
+            abbrevs:
+              - { name: build, abbr: b, snippet: cargo build }
+              - { name: test, abbr: t, snippet: cargo test }
+            ",
+        )
+        .unwrap();
+
+        config.layer(project);
+
+        assert_eq!(
+            config
+                .abbrevs
+                .iter()
+                .map(|abbrev| (abbrev.name(), abbrev.snippet.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("build", "cargo build"),
+                ("test", "cargo test"),
+                ("git", "git"),
+            ]
+        );
     }
 }
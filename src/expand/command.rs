@@ -0,0 +1,136 @@
+use std::io::{self, Read};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use wait_timeout::ChildExt;
+
+#[derive(Debug, Error)]
+pub enum CommandError {
+    #[error("empty command")]
+    Empty,
+
+    #[error("could not run `{0}': {1}")]
+    Spawn(String, io::Error),
+
+    #[error("`{0}' timed out after {1}ms")]
+    Timeout(String, u128),
+
+    #[error("`{0}' failed with {1}: {2}")]
+    Failed(String, String, String),
+}
+
+/// Runs `argv` without a shell and returns its stdout without surrounding whitespace.
+///
+/// The command is killed if it does not exit within `timeout`, and it times out as well if
+/// its output is not closed by then, like when a process it started in the background holds it.
+pub fn run_command(argv: &[String], timeout: Duration) -> Result<String, CommandError> {
+    let (program, args) = argv.split_first().ok_or(CommandError::Empty)?;
+    let deadline = Instant::now() + timeout;
+    let timed_out = || CommandError::Timeout(program.clone(), timeout.as_millis());
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| CommandError::Spawn(program.clone(), err))?;
+
+    // Read the pipes while waiting so that a large output does not block the command
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+
+    let status = match child.wait_timeout(timeout) {
+        Ok(Some(status)) => status,
+        Ok(None) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(timed_out());
+        }
+        Err(err) => return Err(CommandError::Spawn(program.clone(), err)),
+    };
+
+    let stdout = match stdout.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(stdout) => stdout,
+        Err(RecvTimeoutError::Timeout) => return Err(timed_out()),
+        Err(RecvTimeoutError::Disconnected) => String::new(),
+    };
+
+    if !status.success() {
+        let stderr = stderr
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .unwrap_or_default();
+        let message = stderr.lines().next().unwrap_or("").trim().to_string();
+
+        return Err(CommandError::Failed(
+            program.clone(),
+            status.to_string(),
+            message,
+        ));
+    }
+
+    Ok(stdout.trim().to_string())
+}
+
+/// Reads `pipe` to the end in a thread, which is left behind if the pipe is never closed.
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut output);
+        }
+        let _ = sender.send(String::from_utf8_lossy(&output).into_owned());
+    });
+
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_run_command() {
+        let timeout = Duration::from_secs(5);
+
+        assert_eq!(
+            run_command(&argv(&["echo", " v1.0.0 "]), timeout).unwrap(),
+            "v1.0.0"
+        );
+        assert!(matches!(
+            run_command(&argv(&["sh", "-c", "echo oops >&2; exit 3"]), timeout),
+            Err(CommandError::Failed(_, _, message)) if message == "oops"
+        ));
+        assert!(matches!(
+            run_command(&argv(&["sleep", "5"]), Duration::from_millis(100)),
+            Err(CommandError::Timeout(_, 100))
+        ));
+
+        // A process left in the background keeps the output open after the command exits
+        let started = Instant::now();
+        assert!(matches!(
+            run_command(
+                &argv(&["sh", "-c", "sleep 5 & echo hi"]),
+                Duration::from_millis(500)
+            ),
+            Err(CommandError::Timeout(_, 500))
+        ));
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(matches!(
+            run_command(&argv(&["zabbrev-no-such-command"]), timeout),
+            Err(CommandError::Spawn(_, _))
+        ));
+        assert!(matches!(
+            run_command(&[], timeout),
+            Err(CommandError::Empty)
+        ));
+    }
+}
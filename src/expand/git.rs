@@ -0,0 +1,264 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A git repository read from its files, without running git.
+#[derive(Debug)]
+pub struct Repository {
+    root: PathBuf,
+    git_dir: PathBuf,
+    common_dir: PathBuf,
+}
+
+impl Repository {
+    /// Finds the repository containing `dir`.
+    ///
+    /// `.git` may be a file pointing to the git directory, as in worktrees and submodules.
+    pub fn discover(dir: &Path) -> Option<Self> {
+        let root = dir.ancestors().find(|dir| dir.join(".git").exists())?;
+        let dot_git = root.join(".git");
+
+        let git_dir = if dot_git.is_file() {
+            let content = fs::read_to_string(&dot_git).ok()?;
+            let path = content.trim().strip_prefix("gitdir:")?.trim();
+            root.join(path)
+        } else {
+            dot_git
+        };
+
+        // Worktrees share refs and config with the main repository
+        let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+            Ok(path) => git_dir.join(path.trim()),
+            Err(_) => git_dir.clone(),
+        };
+
+        Some(Self {
+            root: root.to_path_buf(),
+            git_dir,
+            common_dir,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the current branch, or the abbreviated commit if HEAD is detached.
+    pub fn branch(&self) -> String {
+        let head = fs::read_to_string(self.git_dir.join("HEAD")).unwrap_or_default();
+        let head = head.trim();
+
+        match head.strip_prefix("ref:") {
+            Some(reference) => {
+                let reference = reference.trim();
+                reference
+                    .strip_prefix("refs/heads/")
+                    .unwrap_or(reference)
+                    .to_string()
+            }
+            None => head.chars().take(7).collect(),
+        }
+    }
+
+    /// Returns the remote of the current branch, `origin`, or the first remote.
+    pub fn remote(&self) -> String {
+        let config = self.config();
+
+        if let Some(remote) = config_value(&config, &format!("branch.{}.remote", self.branch())) {
+            return remote.to_string();
+        }
+
+        let remotes = config
+            .iter()
+            .filter_map(|(name, _)| name.strip_prefix("remote.")?.strip_suffix(".url"))
+            .collect::<Vec<_>>();
+
+        if remotes.contains(&"origin") {
+            "origin".to_string()
+        } else {
+            remotes
+                .first()
+                .map_or(String::new(), |remote| remote.to_string())
+        }
+    }
+
+    /// Returns the upstream of the current branch, like `origin/main`.
+    pub fn upstream(&self) -> String {
+        let config = self.config();
+        let branch = self.branch();
+        let remote = config_value(&config, &format!("branch.{}.remote", branch));
+        let merge = config_value(&config, &format!("branch.{}.merge", branch));
+
+        match (remote, merge) {
+            (Some("."), Some(merge)) => merge.trim_start_matches("refs/heads/").to_string(),
+            (Some(remote), Some(merge)) => {
+                format!("{}/{}", remote, merge.trim_start_matches("refs/heads/"))
+            }
+            _ => String::new(),
+        }
+    }
+
+    /// Returns the branch the remote HEAD points to, falling back to `main` or `master`.
+    pub fn default_branch(&self) -> String {
+        let remote = self.remote();
+        let remote_head = self
+            .common_dir
+            .join("refs/remotes")
+            .join(&remote)
+            .join("HEAD");
+
+        if let Ok(head) = fs::read_to_string(remote_head) {
+            let prefix = format!("refs/remotes/{}/", remote);
+            if let Some(branch) = head.trim().strip_prefix("ref:") {
+                let branch = branch.trim();
+                return branch.strip_prefix(&prefix).unwrap_or(branch).to_string();
+            }
+        }
+
+        ["main", "master"]
+            .iter()
+            .find(|branch| self.has_ref(&format!("refs/heads/{}", branch)))
+            .map_or(String::new(), |branch| branch.to_string())
+    }
+
+    fn has_ref(&self, reference: &str) -> bool {
+        if self.common_dir.join(reference).is_file() {
+            return true;
+        }
+
+        let packed_refs =
+            fs::read_to_string(self.common_dir.join("packed-refs")).unwrap_or_default();
+        packed_refs
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .any(|(_, name)| name == reference)
+    }
+
+    /// Returns the entries of the config as `section.subsection.key` and value.
+    fn config(&self) -> Vec<(String, String)> {
+        let content = fs::read_to_string(self.common_dir.join("config")).unwrap_or_default();
+        let mut section = String::new();
+        let mut entries = Vec::new();
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                // Section names are case-insensitive, subsections are not
+                section = match header.split_once(char::is_whitespace) {
+                    Some((name, subsection)) => format!(
+                        "{}.{}",
+                        name.to_lowercase(),
+                        subsection.trim().trim_matches('"')
+                    ),
+                    None => header.to_lowercase(),
+                };
+                continue;
+            }
+
+            let (key, value) = line.split_once('=').unwrap_or((line, "true"));
+            let value = value.trim().trim_matches('"');
+            entries.push((
+                format!("{}.{}", section, key.trim().to_lowercase()),
+                value.to_string(),
+            ));
+        }
+
+        entries
+    }
+}
+
+fn config_value<'a>(config: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    config
+        .iter()
+        .rev()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expand::template::{render_template, TemplateContext};
+    use crate::testing::TempDir;
+    use std::borrow::Cow;
+
+    #[test]
+    fn test_repository() {
+        let dir = TempDir::new("git");
+        let main = dir.join("main");
+        let worktree = dir.join("worktree");
+        let git_dir = main.join(".git");
+
+        dir.write(git_dir.join("HEAD"), "ref: refs/heads/feature/x\n");
+        dir.write(
+            git_dir.join("config"),
+            concat!(
+                "[core]\n",
+                "\tbare = false\n",
+                "[remote \"upstream\"]\n",
+                "\turl = https://example.com/upstream.git\n",
+                "[remote \"origin\"]\n",
+                "\turl = https://example.com/origin.git\n",
+                "[branch \"feature/x\"]\n",
+                "\tremote = upstream\n",
+                "\tmerge = refs/heads/x\n",
+            ),
+        );
+        dir.write(
+            git_dir.join("refs/remotes/upstream/HEAD"),
+            "ref: refs/remotes/upstream/develop\n",
+        );
+        dir.write(
+            git_dir.join("packed-refs"),
+            "0123456789 refs/heads/master\n",
+        );
+
+        // A worktree with detached HEAD
+        let worktree_git_dir = git_dir.join("worktrees/worktree");
+        dir.write(
+            worktree.join(".git"),
+            &format!("gitdir: {}\n", worktree_git_dir.display()),
+        );
+        dir.write(worktree_git_dir.join("HEAD"), "0123456789abcdef\n");
+        dir.write(worktree_git_dir.join("commondir"), "../..\n");
+        fs::create_dir_all(main.join("src")).unwrap();
+
+        let repository = Repository::discover(&main.join("src")).unwrap();
+        assert_eq!(repository.root(), main);
+        assert_eq!(repository.branch(), "feature/x");
+        assert_eq!(repository.remote(), "upstream");
+        assert_eq!(repository.upstream(), "upstream/x");
+        assert_eq!(repository.default_branch(), "develop");
+
+        let repository = Repository::discover(&worktree).unwrap();
+        assert_eq!(repository.root(), worktree);
+        assert_eq!(repository.branch(), "0123456");
+        assert_eq!(repository.remote(), "origin");
+        assert_eq!(repository.upstream(), "");
+        assert_eq!(repository.default_branch(), "master");
+    }
+
+    #[test]
+    fn test_hostile_branch() {
+        let dir = TempDir::new("git-hostile");
+        dir.write(".git/HEAD", "ref: refs/heads/$(echo INJECTED)`id`\n");
+
+        let context = TemplateContext {
+            cwd: dir.to_path_buf(),
+            ..TemplateContext::default()
+        };
+        let snippet = Cow::from("git push -u origin {{git.branch}} $1");
+
+        // Branch names come with cloned repositories, and must not run in evaluated snippets
+        assert_eq!(
+            render_template(snippet.clone(), &context, true).unwrap(),
+            r"git push -u origin \$(echo INJECTED)\`id\` $1"
+        );
+        assert_eq!(
+            render_template(snippet, &context, false).unwrap(),
+            "git push -u origin $(echo INJECTED)`id` $1"
+        );
+    }
+}
@@ -0,0 +1,907 @@
+use super::placeholder::parse_tab_stops;
+use super::prompt::{fill_prompts, parse_prompts, Prompt};
+use super::recursive::expand_snippet;
+use super::script;
+use super::template::{current_dir, TemplateContext};
+use super::{
+    evaluated_cache_key, find_cursor_command, is_command_separator, is_verbatim, quote_expression,
+    render_abbrev_template, replace_cursor_placeholder, set_locals, store_cache, wrapped_command,
+    WRAP_MARK,
+};
+use crate::cache::Cache;
+use crate::config::abbrev::{Operation, Quote};
+use crate::config::{Abbrev, Config};
+use crate::opt::ExpandLineArgs;
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::ops::Range;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, PartialEq)]
+pub struct Replacement<'a> {
+    pub name: &'a str,
+    pub startindex: usize,
+    pub endindex: usize,
+    pub last_arg: &'a str,
+    pub last_arg_end: usize,
+    pub snippet: Cow<'a, str>,
+    pub operation: &'a Operation,
+    pub evaluate: bool,
+    pub multiline: bool,
+    pub quote: &'a Quote,
+    pub wrapped: Option<&'a str>,
+    /// Replacements in the wrapped command, applied before it is wrapped
+    pub inner: Vec<Replacement<'a>>,
+    pub cache: Option<(String, Duration)>,
+    pub captures: Vec<&'a str>,
+}
+
+pub fn run(args: &ExpandLineArgs) {
+    let buffer = &args.buffer;
+    let cwd = args.cwd.clone().unwrap_or_else(current_dir);
+    let config = Config::load_or_exit(&cwd);
+    let context = TemplateContext {
+        prev: &args.prev,
+        cwd,
+    };
+    let replacements = expand_line(buffer, &config, &context);
+
+    if replacements.is_empty() {
+        return;
+    }
+
+    println!("{}", replacement_code(buffer, &replacements));
+}
+
+/// Returns the zsh code setting `$BUFFER` to `buffer` with `replacements` applied.
+pub fn replacement_code(buffer: &str, replacements: &[Replacement]) -> String {
+    let mut out = format!(
+        "local snippet buffer= zabbrev_buffer={};",
+        escape(Cow::from(buffer))
+    );
+
+    push_replacements(&mut out, buffer, replacements, 0..buffer.len(), 0);
+    out.push_str("BUFFER=$buffer;CURSOR=$#BUFFER;");
+    out
+}
+
+/// Appends the zsh code adding `range` of `buffer` with `replacements` applied to `$buffer`.
+fn push_replacements(
+    out: &mut String,
+    buffer: &str,
+    replacements: &[Replacement],
+    range: Range<usize>,
+    depth: usize,
+) {
+    let mut index = range.start;
+
+    for r in replacements {
+        out.push_str(&format!(
+            "buffer+={};",
+            escape(Cow::from(&buffer[index..r.startindex]))
+        ));
+
+        // The wrapped command is built in `$buffer` with its replacements, then taken out of it
+        let wrap_snippet = match r.wrapped {
+            Some(wrapped) => {
+                let start = format!("zabbrev_wrap_start{}", depth);
+                let wrapped_range = r.startindex..r.startindex + wrapped.len();
+
+                out.push_str(&format!("local {}=$#buffer;", start));
+                push_replacements(out, buffer, &r.inner, wrapped_range, depth + 1);
+                out.push_str(&format!(
+                    r#"local wrapped="${{buffer:${}}}";buffer="${{buffer:0:${}}}";"#,
+                    start, start
+                ));
+
+                r#"snippet="${snippet/$'\x1e'/$wrapped}";"#
+            }
+            None => "",
+        };
+
+        let last_arg = escape(Cow::from(r.last_arg));
+        let snippet = escape(Cow::from(r.snippet.as_ref()));
+        let evaluate = if r.evaluate { "(e)" } else { "" };
+        let store_cache = store_cache(&r.cache, r.name);
+
+        // Evaluated snippets are given the command line around their trigger
+        let set_locals = match find_cursor_command(buffer, r.last_arg_end) {
+            Some(c) if r.evaluate => set_locals(buffer, &c, &r.captures),
+            _ => String::new(),
+        };
+        let quote_snippet = match r.quote {
+            Quote::None => String::new(),
+            _ if r.wrapped.is_some() => String::new(),
+            quote => format!(r#"snippet="{}";"#, quote_expression(quote, "snippet")),
+        };
+        let lines = if r.multiline {
+            "$snippet"
+        } else {
+            "${(pj: :)${(@f)snippet}}"
+        };
+
+        let (joint_append, joint_prepend) = joints(r.operation);
+
+        out.push_str(&format!(
+            r#"snippet={};set -- {};{}snippet="${{{}snippet}}" || return;{}{}{}buffer+="{}{}{}";"#,
+            snippet,
+            last_arg,
+            set_locals,
+            evaluate,
+            store_cache,
+            quote_snippet,
+            wrap_snippet,
+            joint_append,
+            lines,
+            joint_prepend
+        ));
+
+        index = r.endindex;
+    }
+
+    out.push_str(&format!(
+        "buffer+={};",
+        escape(Cow::from(&buffer[index..range.end]))
+    ));
+}
+
+fn joints(operation: &Operation) -> (&'static str, &'static str) {
+    match operation {
+        Operation::Append => (" ", ""),
+        Operation::Prepend => ("", " "),
+        _ => ("", ""),
+    }
+}
+
+pub fn expand_line<'a>(
+    buffer: &'a str,
+    config: &'a Config,
+    context: &TemplateContext,
+) -> Vec<Replacement<'a>> {
+    let replacements = find_matches(buffer, config, 0..buffer.len(), &context.cwd)
+        .into_iter()
+        .filter_map(|m| {
+            let r = replacement(buffer, config, context, &m)?;
+            Some((m, r))
+        })
+        .collect();
+
+    apply_in_order(buffer, replacements)
+}
+
+/// Returns the replacement of the match `m`, or `None` if its snippet cannot be made.
+fn replacement<'a>(
+    buffer: &'a str,
+    config: &'a Config,
+    context: &TemplateContext,
+    m: &Match<'a, 'a>,
+) -> Option<Replacement<'a>> {
+    let (snippet, evaluate) = expand_snippet(config, buffer, m, &context.cwd)?;
+    let snippet = replace_cursor_placeholder(config, m.abbrev, snippet, "");
+    let snippet = render_abbrev_template(m.abbrev, snippet, evaluate, context)?;
+
+    // Nobody is asked, so prompts take their defaults
+    let prompts = parse_prompts(&snippet);
+    let answers = prompts
+        .iter()
+        .map(Prompt::default_answer)
+        .collect::<Vec<_>>();
+    let snippet = fill_prompts(snippet, &answers, false);
+
+    // Evaluated snippets are cached like when expanded by a key
+    let mut cache = None;
+    let mut cached = false;
+    let (snippet, evaluate) = match (m.abbrev.cache, find_cursor_command(buffer, m.last_arg_end)) {
+        (Some(ttl), Some(c)) if evaluate => {
+            // The answers are already in the snippet
+            let key = evaluated_cache_key(m.abbrev, &snippet, buffer, &c, &[], &context.cwd);
+
+            match Cache::open().and_then(|c| c.get(&key)) {
+                Some(value) => {
+                    cached = true;
+                    (Cow::from(value), false)
+                }
+                None => {
+                    cache = Some((key, ttl));
+                    (snippet, true)
+                }
+            }
+        }
+        _ => (snippet, evaluate),
+    };
+
+    let snippet = if evaluate || cached || is_verbatim(m.abbrev) {
+        snippet
+    } else {
+        parse_tab_stops(snippet, "").0
+    };
+
+    let wrapped = match m.abbrev.operation {
+        Operation::Wrap => Some(command_to_rewrite(buffer, m)),
+        _ => None,
+    };
+
+    Some(Replacement {
+        name: m.abbrev.name(),
+        startindex: m.startindex,
+        endindex: m.endindex,
+        last_arg: m.last_arg,
+        last_arg_end: m.last_arg_end,
+        snippet,
+        operation: &m.abbrev.operation,
+        evaluate,
+        multiline: m.abbrev.multiline,
+        quote: &m.abbrev.quote,
+        wrapped,
+        inner: Vec::new(),
+        cache,
+        captures: m.abbrev.captures(m.last_arg),
+    })
+}
+
+/// Returns the command wrapped or substituted by the match `m`.
+fn command_to_rewrite<'a>(buffer: &'a str, m: &Match) -> &'a str {
+    let trigger_index = m.last_arg_end - m.last_arg.len();
+    wrapped_command(
+        buffer,
+        m.abbrev,
+        m.startindex,
+        trigger_index,
+        m.last_arg_end,
+    )
+}
+
+/// Applies the replacements from left to right by their triggers, and returns the ones left.
+///
+/// A `wrap` or `substitute` rewrites its command with the earlier replacements in it applied,
+/// and any other replacement wins over the earlier ones it overwrites.
+fn apply_in_order<'a>(
+    buffer: &'a str,
+    mut replacements: Vec<(Match<'a, 'a>, Replacement<'a>)>,
+) -> Vec<Replacement<'a>> {
+    replacements.sort_by_key(|(m, _)| m.last_arg_end);
+
+    let mut applied: Vec<Replacement> = Vec::new();
+
+    for (m, mut r) in replacements {
+        if matches!(m.abbrev.operation, Operation::Wrap | Operation::Substitute) {
+            let command_end = m.startindex + command_to_rewrite(buffer, &m).len();
+            let (inner, outer): (Vec<_>, Vec<_>) = applied
+                .into_iter()
+                .partition(|a| r.startindex <= a.startindex && a.endindex <= command_end);
+            applied = outer;
+
+            if m.abbrev.operation == Operation::Wrap {
+                r.inner = inner;
+            } else if !inner.is_empty() {
+                let command = expanded_text(buffer, &inner, r.startindex..command_end);
+
+                match command.and_then(|c| Some(m.abbrev.substitute(&c)?.into_owned())) {
+                    Some(snippet) => r.snippet = Cow::from(snippet),
+                    // The output of evaluated snippets is only known to zsh, so they are kept
+                    // and the substitution is given up
+                    None => {
+                        applied.extend(inner);
+                        applied.sort_by_key(|a| (a.startindex, a.endindex));
+                        continue;
+                    }
+                }
+            }
+        }
+
+        applied.retain(|a| !(a.startindex < r.endindex && r.startindex < a.endindex));
+
+        let index =
+            applied.partition_point(|a| (a.startindex, a.endindex) < (r.startindex, r.endindex));
+        applied.insert(index, r);
+    }
+
+    applied
+}
+
+/// Returns `range` of `buffer` with `replacements` applied, unless any of them is known only
+/// after zsh evaluates or quotes it.
+fn expanded_text(
+    buffer: &str,
+    replacements: &[Replacement],
+    range: Range<usize>,
+) -> Option<String> {
+    let mut text = String::new();
+    let mut index = range.start;
+
+    for r in replacements {
+        if r.evaluate || *r.quote != Quote::None {
+            return None;
+        }
+
+        let snippet = match r.wrapped {
+            Some(wrapped) => {
+                let wrapped_range = r.startindex..r.startindex + wrapped.len();
+                let wrapped = expanded_text(buffer, &r.inner, wrapped_range)?;
+                r.snippet.replacen(WRAP_MARK, &wrapped, 1)
+            }
+            None => r.snippet.to_string(),
+        };
+        let snippet = if r.multiline {
+            snippet
+        } else {
+            snippet.lines().collect::<Vec<_>>().join(" ")
+        };
+        let (joint_append, joint_prepend) = joints(r.operation);
+
+        text.push_str(&buffer[index..r.startindex]);
+        text.push_str(joint_append);
+        text.push_str(&snippet);
+        text.push_str(joint_prepend);
+        index = r.endindex;
+    }
+
+    text.push_str(&buffer[index..range.end]);
+    Some(text)
+}
+
+#[derive(Debug)]
+pub struct Match<'a, 'b> {
+    pub startindex: usize,
+    pub endindex: usize,
+    pub last_arg: &'b str,
+    pub last_arg_end: usize,
+    pub command: &'b str,
+    pub abbrev: &'a Abbrev,
+}
+
+/// Finds the abbrevs triggered by the words in `range`, sorted by position.
+pub fn find_matches<'a, 'b>(
+    buffer: &'b str,
+    config: &'a Config,
+    range: Range<usize>,
+    cwd: &Path,
+) -> Vec<Match<'a, 'b>> {
+    let mut matches: Vec<Match> = Vec::new();
+
+    for words in split_commands(buffer) {
+        let (command_index, context_end) = match words.first() {
+            Some(&word) => word,
+            None => continue,
+        };
+
+        for (i, &(start, end)) in words.iter().enumerate() {
+            if start < range.start || range.end < end {
+                continue;
+            }
+
+            // Only words typed by the user are matched, never inserted snippets
+            let command = &buffer[command_index..end];
+            let context = if i == 0 {
+                ""
+            } else {
+                &buffer[command_index..context_end]
+            };
+            let last_arg = &buffer[start..end];
+
+            let abbrev = match config.abbrevs.iter().find(|abbr| {
+                abbr.is_match(command, context, last_arg, i <= 1)
+                    && script::is_match(abbr, command, cwd, config)
+            }) {
+                Some(abbrev) => abbrev,
+                None => continue,
+            };
+
+            let (startindex, endindex) = match abbrev.operation {
+                Operation::ReplaceSelf => (start, end),
+                Operation::ReplaceCommand => (command_index, context_end),
+                Operation::ReplaceAll => (command_index, end),
+                Operation::ReplaceBuffer => (0, buffer.len()),
+                Operation::Append => (end, end),
+                Operation::Prepend => (command_index, command_index),
+                Operation::Wrap | Operation::Substitute => (command_index, end),
+            };
+
+            if startindex < range.start || range.end < endindex {
+                continue;
+            }
+
+            // Overlapping matches are all kept, as which of them wins depends on their snippets
+            let index =
+                matches.partition_point(|m| (m.startindex, m.endindex) < (startindex, endindex));
+
+            matches.insert(
+                index,
+                Match {
+                    startindex,
+                    endindex,
+                    last_arg,
+                    last_arg_end: end,
+                    command,
+                    abbrev,
+                },
+            );
+        }
+    }
+
+    matches
+}
+
+/// Splits `line` into commands of words, given as byte ranges.
+pub fn split_commands(line: &str) -> Vec<Vec<(usize, usize)>> {
+    let mut commands = vec![Vec::new()];
+    let mut word_start = None;
+
+    for (i, c) in line.char_indices() {
+        if c.is_whitespace() || is_command_separator(c) {
+            if let Some(start) = word_start.take() {
+                commands.last_mut().unwrap().push((start, i));
+            }
+            if is_command_separator(c) {
+                commands.push(Vec::new());
+            }
+        } else if word_start.is_none() {
+            word_start = Some(i);
+        }
+    }
+
+    if let Some(start) = word_start {
+        commands.last_mut().unwrap().push((start, line.len()));
+    }
+
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> Config {
+        Config::load_from_str(
+            r"
+            abbrevs:
+              - name: git
+                abbr: g
+                snippet: git
+
+              - name: git commit
+                abbr: c
+                snippet: commit
+                global: false
+                context: 'git'
+
+              - name: '>/dev/null'
+                abbr: 'null'
+                snippet: '>/dev/null'
+                global: true
+
+              - name: $HOME
+                abbr: home
+                snippet: $HOME
+                evaluate: true
+
+              - name: default argument
+                abbr: rm
+                snippet: -i
+                operation: append
+
+              - name: fake command
+                context: 'extract'
+                abbr-regex: '\.tar$'
+                snippet: 'tar -xvf'
+                operation: replace-command
+
+              - name: like a function
+                context: 'mkdircd'
+                abbr-regex: '.+'
+                snippet: 'mkdir -p $1 && cd $1'
+                operation: replace-all
+                evaluate: true
+
+              - name: associated command
+                abbr-regex: '\.java$'
+                snippet: 'java -jar'
+                operation: prepend
+
+              - name: git commit -m
+                abbr: cm
+                snippet: commit -m '{{}}'
+                context: 'git'
+
+              - name: docker run
+                abbr: dr
+                snippet: docker run --name ${1:name} ${2:image}
+
+              - name: kubectl use-context
+                abbr: kuc
+                snippet: kubectl config use-context {{choice:dev|prod}}{{input:Suffix}}
+
+              - name: page help
+                abbr: --help
+                global: true
+                operation: wrap
+                after: ' | less'
+                keep-trigger: true
+
+              - name: cargo test
+                abbr: t
+                snippet: test
+                context: cargo
+
+              - name: no capture
+                context: cargo
+                abbr: '!v'
+                global: true
+                operation: substitute
+                pattern: '$'
+                replacement: ' -- --nocapture'
+            ",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_expand_line() {
+        let config = test_config();
+
+        struct Scenario<'a> {
+            pub testname: &'a str,
+            pub buffer: &'a str,
+            pub expected: Vec<Replacement<'a>>,
+        }
+
+        let scenarios = &[
+            Scenario {
+                testname: "empty",
+                buffer: "",
+                expected: vec![],
+            },
+            Scenario {
+                testname: "no matched abbr",
+                buffer: "echo hello",
+                expected: vec![],
+            },
+            Scenario {
+                testname: "every command",
+                buffer: "g c -m hello && g",
+                expected: vec![
+                    Replacement {
+                        name: "git",
+                        startindex: 0,
+                        endindex: 1,
+                        last_arg: "g",
+                        last_arg_end: 1,
+                        snippet: "git".into(),
+                        operation: &Operation::ReplaceSelf,
+                        evaluate: false,
+                        multiline: false,
+                        quote: &Quote::None,
+                        wrapped: None,
+                        inner: vec![],
+                        cache: None,
+                        captures: vec![],
+                    },
+                    Replacement {
+                        name: "git",
+                        startindex: 16,
+                        endindex: 17,
+                        last_arg: "g",
+                        last_arg_end: 17,
+                        snippet: "git".into(),
+                        operation: &Operation::ReplaceSelf,
+                        evaluate: false,
+                        multiline: false,
+                        quote: &Quote::None,
+                        wrapped: None,
+                        inner: vec![],
+                        cache: None,
+                        captures: vec![],
+                    },
+                ],
+            },
+            Scenario {
+                testname: "context is the typed command",
+                buffer: "git c home",
+                expected: vec![Replacement {
+                    name: "git commit",
+                    startindex: 4,
+                    endindex: 5,
+                    last_arg: "c",
+                    last_arg_end: 5,
+                    snippet: "commit".into(),
+                    operation: &Operation::ReplaceSelf,
+                    evaluate: false,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    inner: vec![],
+                    cache: None,
+                    captures: vec![],
+                }],
+            },
+            Scenario {
+                testname: "global abbrs",
+                buffer: "echo null null",
+                expected: vec![
+                    Replacement {
+                        name: ">/dev/null",
+                        startindex: 5,
+                        endindex: 9,
+                        last_arg: "null",
+                        last_arg_end: 9,
+                        snippet: ">/dev/null".into(),
+                        operation: &Operation::ReplaceSelf,
+                        evaluate: false,
+                        multiline: false,
+                        quote: &Quote::None,
+                        wrapped: None,
+                        inner: vec![],
+                        cache: None,
+                        captures: vec![],
+                    },
+                    Replacement {
+                        name: ">/dev/null",
+                        startindex: 10,
+                        endindex: 14,
+                        last_arg: "null",
+                        last_arg_end: 14,
+                        snippet: ">/dev/null".into(),
+                        operation: &Operation::ReplaceSelf,
+                        evaluate: false,
+                        multiline: false,
+                        quote: &Quote::None,
+                        wrapped: None,
+                        inner: vec![],
+                        cache: None,
+                        captures: vec![],
+                    },
+                ],
+            },
+            Scenario {
+                testname: "append and replace-command",
+                buffer: "rm foo; extract a.tar",
+                expected: vec![
+                    Replacement {
+                        name: "default argument",
+                        startindex: 2,
+                        endindex: 2,
+                        last_arg: "rm",
+                        last_arg_end: 2,
+                        snippet: "-i".into(),
+                        operation: &Operation::Append,
+                        evaluate: false,
+                        multiline: false,
+                        quote: &Quote::None,
+                        wrapped: None,
+                        inner: vec![],
+                        cache: None,
+                        captures: vec![],
+                    },
+                    Replacement {
+                        name: "fake command",
+                        startindex: 8,
+                        endindex: 15,
+                        last_arg: "a.tar",
+                        last_arg_end: 21,
+                        snippet: "tar -xvf".into(),
+                        operation: &Operation::ReplaceCommand,
+                        evaluate: false,
+                        multiline: false,
+                        quote: &Quote::None,
+                        wrapped: None,
+                        inner: vec![],
+                        cache: None,
+                        captures: vec![],
+                    },
+                ],
+            },
+            Scenario {
+                testname: "prepend",
+                buffer: "echo; a.java b.java",
+                expected: vec![Replacement {
+                    name: "associated command",
+                    startindex: 6,
+                    endindex: 6,
+                    last_arg: "a.java",
+                    last_arg_end: 12,
+                    snippet: "java -jar".into(),
+                    operation: &Operation::Prepend,
+                    evaluate: false,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    inner: vec![],
+                    cache: None,
+                    captures: vec![],
+                }],
+            },
+            Scenario {
+                testname: "cursor placeholder is removed",
+                buffer: "git cm",
+                expected: vec![Replacement {
+                    name: "git commit -m",
+                    startindex: 4,
+                    endindex: 6,
+                    last_arg: "cm",
+                    last_arg_end: 6,
+                    snippet: "commit -m ''".into(),
+                    operation: &Operation::ReplaceSelf,
+                    evaluate: false,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    inner: vec![],
+                    cache: None,
+                    captures: vec![],
+                }],
+            },
+            Scenario {
+                testname: "tab stops are replaced with defaults",
+                buffer: "dr",
+                expected: vec![Replacement {
+                    name: "docker run",
+                    startindex: 0,
+                    endindex: 2,
+                    last_arg: "dr",
+                    last_arg_end: 2,
+                    snippet: "docker run --name name image".into(),
+                    operation: &Operation::ReplaceSelf,
+                    evaluate: false,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    inner: vec![],
+                    cache: None,
+                    captures: vec![],
+                }],
+            },
+            Scenario {
+                testname: "replace-all overwrites earlier expansions",
+                buffer: "mkdircd foo bar",
+                expected: vec![Replacement {
+                    name: "like a function",
+                    startindex: 0,
+                    endindex: 11,
+                    last_arg: "foo",
+                    last_arg_end: 11,
+                    snippet: "mkdir -p $1 && cd $1".into(),
+                    operation: &Operation::ReplaceAll,
+                    evaluate: true,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    inner: vec![],
+                    cache: None,
+                    captures: vec![],
+                }],
+            },
+            Scenario {
+                testname: "wrap keeping the trigger",
+                buffer: "echo; git  --help",
+                expected: vec![Replacement {
+                    name: "page help",
+                    startindex: 6,
+                    endindex: 17,
+                    last_arg: "--help",
+                    last_arg_end: 17,
+                    snippet: "\x1e | less".into(),
+                    operation: &Operation::Wrap,
+                    evaluate: false,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: Some("git  --help"),
+                    inner: vec![],
+                    cache: None,
+                    captures: vec![],
+                }],
+            },
+            Scenario {
+                testname: "substitute",
+                buffer: "cargo test !v",
+                expected: vec![Replacement {
+                    name: "no capture",
+                    startindex: 0,
+                    endindex: 13,
+                    last_arg: "!v",
+                    last_arg_end: 13,
+                    snippet: "cargo test -- --nocapture".into(),
+                    operation: &Operation::Substitute,
+                    evaluate: false,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    inner: vec![],
+                    cache: None,
+                    captures: vec![],
+                }],
+            },
+            Scenario {
+                testname: "wrap keeps earlier expansions",
+                buffer: "g status --help",
+                expected: vec![Replacement {
+                    name: "page help",
+                    startindex: 0,
+                    endindex: 15,
+                    last_arg: "--help",
+                    last_arg_end: 15,
+                    snippet: "\x1e | less".into(),
+                    operation: &Operation::Wrap,
+                    evaluate: false,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: Some("g status --help"),
+                    inner: vec![Replacement {
+                        name: "git",
+                        startindex: 0,
+                        endindex: 1,
+                        last_arg: "g",
+                        last_arg_end: 1,
+                        snippet: "git".into(),
+                        operation: &Operation::ReplaceSelf,
+                        evaluate: false,
+                        multiline: false,
+                        quote: &Quote::None,
+                        wrapped: None,
+                        inner: vec![],
+                        cache: None,
+                        captures: vec![],
+                    }],
+                    cache: None,
+                    captures: vec![],
+                }],
+            },
+            Scenario {
+                testname: "substitute the expanded command",
+                buffer: "cargo t !v",
+                expected: vec![Replacement {
+                    name: "no capture",
+                    startindex: 0,
+                    endindex: 10,
+                    last_arg: "!v",
+                    last_arg_end: 10,
+                    snippet: "cargo test -- --nocapture".into(),
+                    operation: &Operation::Substitute,
+                    evaluate: false,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    inner: vec![],
+                    cache: None,
+                    captures: vec![],
+                }],
+            },
+            Scenario {
+                testname: "prompts take defaults",
+                buffer: "kuc",
+                expected: vec![Replacement {
+                    name: "kubectl use-context",
+                    startindex: 0,
+                    endindex: 3,
+                    last_arg: "kuc",
+                    last_arg_end: 3,
+                    snippet: "kubectl config use-context dev".into(),
+                    operation: &Operation::ReplaceSelf,
+                    evaluate: false,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    inner: vec![],
+                    cache: None,
+                    captures: vec![],
+                }],
+            },
+        ];
+
+        for s in scenarios {
+            let actual = expand_line(s.buffer, &config, &TemplateContext::default());
+
+            assert_eq!(actual, s.expected, "{}", s.testname);
+        }
+    }
+}
+
+#[test]
+fn test_split_commands() {
+    assert_eq!(split_commands(""), vec![vec![]]);
+    assert_eq!(split_commands("git  commit"), vec![vec![(0, 3), (5, 11)]]);
+    assert_eq!(
+        split_commands("echo a|cat"),
+        vec![vec![(0, 4), (5, 6)], vec![(7, 10)]]
+    );
+}
@@ -1,114 +1,500 @@
-use crate::config::abbrev::Operation;
-use crate::config::Config;
-use crate::opt::ExpandArgs;
+pub mod command;
+pub mod git;
+pub mod line;
+pub mod placeholder;
+pub mod prompt;
+pub mod recursive;
+pub mod script;
+pub mod template;
+
+use crate::cache::{cache_key, Cache};
+use crate::config::abbrev::{Action, Operation, Quote};
+use crate::config::{Abbrev, Config};
+use crate::opt::{ExpandArgs, RenderArgs};
+use ansi_term::Color;
+use git::Repository;
+use line::Match;
+use placeholder::parse_tab_stops;
+use prompt::{fill_prompts, parse_prompts, Prompt};
+use recursive::expand_snippet;
 use shell_escape::escape;
 use std::borrow::Cow;
+use std::path::Path;
+use std::time::Duration;
+use template::{current_dir, render_template, requires_repository, TemplateContext, TemplateError};
+
+/// Replaces the cursor placeholder in snippets passed to zsh, as it survives `(e)` expansion
+static CURSOR_MARK: &str = "\x1f";
+
+/// Marks where the wrapped command goes in snippets passed to zsh, so it is not evaluated
+static WRAP_MARK: &str = "\x1e";
 
 #[derive(Debug, PartialEq)]
 pub struct ExpandResult<'a> {
-    pub lbuffer: &'a str,
+    pub name: &'a str,
+    pub buffer: &'a str,
     pub startindex: usize,
     pub endindex: usize,
+    /// End of the text replaced by the expansion, which may extend past the cursor
+    pub consumed_end: usize,
     pub last_arg: &'a str,
-    pub snippet: &'a str,
+    pub snippet: Cow<'a, str>,
     pub evaluate: bool,
-    pub rbuffer: &'a str,
+    pub multiline: bool,
+    pub quote: &'a Quote,
+    pub wrapped: Option<&'a str>,
+    pub action: &'a Action,
+    pub prompts: Vec<Prompt>,
+    pub captures: Vec<&'a str>,
+    pub cache: Option<(String, Duration)>,
+    pub stops: Vec<(usize, usize)>,
 }
 
 pub fn run(args: &ExpandArgs) {
-    if let Some(result) = expand(args, &Config::load_or_exit()) {
-        let lbuffer_prev = escape(Cow::from(&result.lbuffer[..result.startindex]));
-        let lbuffer_post = escape(Cow::from(&result.lbuffer[result.endindex..]));
-        let last_arg = escape(Cow::from(result.last_arg));
-        let snippet = escape(Cow::from(result.snippet));
-        let rbuffer = escape(Cow::from(result.rbuffer));
-        let evaluate = if result.evaluate { "(e)" } else { "" };
-
-        let (joint_append, joint_prepend) = if result.startindex == result.endindex {
-            if result.startindex == result.lbuffer.len() {
-                (" ", "")
-            } else {
-                ("", " ")
-            }
+    print_expansion(args, &[]);
+}
+
+/// Expands like `run` with the answers to the prompts of the snippet.
+pub fn render(args: &RenderArgs) {
+    print_expansion(&args.expand, &args.answers);
+}
+
+fn print_expansion(args: &ExpandArgs, answers: &[String]) {
+    let buffer = format!("{}{}", args.lbuffer, args.rbuffer);
+    let cursor = args.lbuffer.len();
+    let cwd = args.cwd.clone().unwrap_or_else(current_dir);
+    let config = Config::load_or_exit(&cwd);
+
+    let context = TemplateContext {
+        prev: &args.prev,
+        cwd,
+    };
+    let candidates = find_candidates(&buffer, cursor, &config, &context.cwd);
+
+    if candidates.is_empty() {
+        return;
+    }
+
+    let index = args.index % candidates.len();
+    let result = match expand(
+        &buffer,
+        cursor,
+        &config,
+        candidates[index],
+        answers,
+        &context,
+    ) {
+        Some(result) => result,
+        None => return,
+    };
+
+    // Ask for the answers, then `zabbrev render` does the expansion
+    if !result.prompts.is_empty() {
+        let prompts = result
+            .prompts
+            .iter()
+            .map(|prompt| match prompt {
+                Prompt::Input(label) => format!(" input {}", escape(Cow::from(label))),
+                Prompt::Choice(options) => {
+                    format!(" choice {}", escape(Cow::from(options.join("|"))))
+                }
+            })
+            .collect::<String>();
+
+        println!("__zabbrev_prompts=({});", prompts.trim_start());
+        return;
+    }
+
+    let mut lbuffer_prev = &result.buffer[..result.startindex];
+    let mut lbuffer_post = &result.buffer[result.endindex..result.consumed_end];
+    let mut rbuffer = &result.buffer[result.consumed_end..];
+
+    // The rest of the line is pushed, leaving the snippet alone in the buffer
+    let push_line = if *result.action == Action::PushLine {
+        let rest = format!("{}{}{}", lbuffer_prev, lbuffer_post, rbuffer);
+        lbuffer_prev = "";
+        lbuffer_post = "";
+        rbuffer = "";
+        format!("__zabbrev_pushed={};", escape(Cow::from(rest.trim())))
+    } else {
+        String::new()
+    };
+
+    let lbuffer_prev = escape(Cow::from(lbuffer_prev));
+    let lbuffer_post = escape(Cow::from(lbuffer_post));
+    let last_arg = escape(Cow::from(result.last_arg));
+    let snippet = escape(Cow::from(result.snippet.as_ref()));
+    let rbuffer = escape(Cow::from(rbuffer));
+    let evaluate = if result.evaluate { "(e)" } else { "" };
+    let quote_snippet = match result.quote {
+        Quote::None => String::new(),
+        _ if result.wrapped.is_some() => String::new(),
+        quote => format!(
+            concat!(
+                r#"local left="${{snippet%%$'\x1f'*}}" right=;"#,
+                r#"if [[ "$snippet" == *$'\x1f'* ]]; then right="${{snippet#*$'\x1f'}}";right=$'\x1f'"{}";fi;"#,
+                r#"snippet="{}$right";"#,
+            ),
+            quote_expression(quote, "right"),
+            quote_expression(quote, "left"),
+        ),
+    };
+    let join_lines = if result.multiline {
+        ""
+    } else {
+        r#"snippet="${(pj: :)${(@f)snippet}}";"#
+    };
+    let wrap_snippet = match result.wrapped {
+        Some(wrapped) => format!(
+            r#"local wrapped={};snippet="${{snippet/$'\x1e'/$wrapped}}";"#,
+            escape(Cow::from(wrapped))
+        ),
+        None => String::new(),
+    };
+
+    let (joint_append, joint_prepend) = if *result.action == Action::PushLine {
+        ("", "")
+    } else if result.startindex == result.endindex {
+        if result.startindex == result.consumed_end {
+            (" ", "")
         } else {
-            ("", "")
-        };
+            ("", " ")
+        }
+    } else {
+        ("", "")
+    };
 
-        println!(
-            r#"local snippet={};set -- {};snippet="${{{}snippet}}";[[ $? -eq 0 ]] && {{ LBUFFER={}"{}${{(pj: :)${{(@f)snippet}}}}{}"{};RBUFFER={};}};"#,
-            snippet,
-            last_arg,
-            evaluate,
-            lbuffer_prev,
-            joint_append,
-            joint_prepend,
-            lbuffer_post,
-            rbuffer
-        );
+    // Hand back the buffer before expansion so that it can be restored
+    println!(
+        "__zabbrev_lbuffer={};__zabbrev_rbuffer={};",
+        escape(Cow::from(&args.lbuffer)),
+        escape(Cow::from(&args.rbuffer))
+    );
+
+    if candidates.len() > 1 {
+        let candidates = candidates
+            .iter()
+            .enumerate()
+            .map(|(i, abbrev)| {
+                if i == index {
+                    format!("[{}]", abbrev.name())
+                } else {
+                    abbrev.name().to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("  ");
+
+        println!("zle -M {};", escape(Cow::from(candidates)));
     }
+
+    let stops = result
+        .stops
+        .iter()
+        .map(|(start, end)| format!(" {}:{}", start, end))
+        .collect::<String>();
+    let set_stops = if stops.is_empty() {
+        String::new()
+    } else {
+        format!("__zabbrev::set-stops $#prefix{};", stops)
+    };
+
+    // The command line is given to evaluated snippets
+    let set_locals = match find_cursor_command(&buffer, cursor) {
+        Some(c) if result.evaluate => set_locals(&buffer, &c, &result.captures),
+        _ => String::new(),
+    };
+    let store_cache = store_cache(&result.cache, result.name);
+    let set_answers = if answers.is_empty() {
+        String::new()
+    } else {
+        let answers = answers
+            .iter()
+            .map(|answer| escape(Cow::from(answer.as_str())))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        format!("local -a zabbrev_answers=({});", answers)
+    };
+
+    let action = match result.action {
+        Action::None => "",
+        Action::AcceptLine => "accept-line",
+        Action::PushLine => "push-line",
+        Action::EditCommandLine => "edit-command-line",
+        Action::ClearScreen => "clear-screen",
+    };
+    let set_action = if action.is_empty() {
+        String::new()
+    } else {
+        format!("{}__zabbrev_action={};", push_line, action)
+    };
+
+    // The snippet is inserted left of the cursor unless it contains the cursor mark
+    println!(
+        concat!(
+            r#"{set_answers}local zabbrev_buffer={buffer} snippet={snippet};set -- {last_arg};{set_locals}snippet="${{{evaluate}snippet}}";[[ $? -eq 0 ]] && {{ {store_cache}"#,
+            r#"{quote_snippet}{join_lines}{wrap_snippet}local prefix={lbuffer_prev}"{joint_append}";"#,
+            r#"LBUFFER="$prefix${{snippet%%$'\x1f'*}}";"#,
+            r#"if [[ "$snippet" == *$'\x1f'* ]]; then __zabbrev_placeholder=1;RBUFFER="${{snippet#*$'\x1f'}}{joint_prepend}"{lbuffer_post}{rbuffer};"#,
+            r#"else LBUFFER+="{joint_prepend}"{lbuffer_post};RBUFFER={rbuffer};fi;{set_stops}{set_action}}};"#,
+        ),
+        set_answers = set_answers,
+        set_locals = set_locals,
+        store_cache = store_cache,
+        buffer = escape(Cow::from(result.buffer)),
+        snippet = snippet,
+        last_arg = last_arg,
+        evaluate = evaluate,
+        quote_snippet = quote_snippet,
+        join_lines = join_lines,
+        wrap_snippet = wrap_snippet,
+        lbuffer_prev = lbuffer_prev,
+        joint_append = joint_append,
+        joint_prepend = joint_prepend,
+        lbuffer_post = lbuffer_post,
+        rbuffer = rbuffer,
+        set_stops = set_stops,
+        set_action = set_action,
+    );
+}
+
+/// The command containing the cursor, split into words.
+#[derive(Debug, Clone, Copy)]
+struct CursorCommand<'a> {
+    command_index: usize,
+    /// The command up to the cursor
+    command: &'a str,
+    context: &'a str,
+    internal_args: &'a str,
+    last_arg_index: usize,
+    last_arg_end: usize,
+    last_arg: &'a str,
 }
 
-fn expand<'a>(args: &'a ExpandArgs, config: &'a Config) -> Option<ExpandResult<'a>> {
-    let lbuffer = &args.lbuffer;
-    let rbuffer = &args.rbuffer;
+/// Finds the command around `cursor`, unless no word ends at or spans the cursor.
+fn find_cursor_command(buffer: &str, cursor: usize) -> Option<CursorCommand<'_>> {
+    let (lbuffer, rbuffer) = buffer.split_at(cursor);
 
     let command_index = find_last_command_index(lbuffer);
     let command = lbuffer[command_index..].trim_start();
 
-    let (args_until_last, last_arg) = command
+    let (args_until_last, last_arg_left) = command
         .rsplit_once(char::is_whitespace)
         .unwrap_or(("", command));
 
-    if last_arg.is_empty() {
+    if last_arg_left.is_empty() {
         return None;
     }
 
+    // The word under the cursor continues into $RBUFFER
+    let last_arg_right_len = rbuffer
+        .find(|c: char| c.is_whitespace() || is_command_separator(c))
+        .unwrap_or(rbuffer.len());
+
+    let last_arg_index = cursor - last_arg_left.len();
+    let last_arg_end = cursor + last_arg_right_len;
+    let last_arg = &buffer[last_arg_index..last_arg_end];
+
     let (context, internal_args) = args_until_last
         .split_once(char::is_whitespace)
         .unwrap_or((args_until_last, ""));
 
-    let abbrev = config
+    let command_index = cursor - command.len();
+
+    Some(CursorCommand {
+        command_index,
+        command,
+        context,
+        internal_args,
+        last_arg_index,
+        last_arg_end,
+        last_arg,
+    })
+}
+
+/// Returns the cache key of a snippet evaluated at `c`, covering everything the snippet is given:
+/// the trigger as `$1`, the command line in `$ZABBREV_COMMAND` and so on, and the answers.
+fn evaluated_cache_key(
+    abbrev: &Abbrev,
+    snippet: &str,
+    buffer: &str,
+    c: &CursorCommand,
+    answers: &[String],
+    cwd: &Path,
+) -> String {
+    let full_command = &buffer[c.command_index..c.last_arg_end];
+    let rbuffer = &buffer[c.last_arg_end..];
+    let source = format!(
+        "{}\0{}\0{}\0{}",
+        snippet,
+        full_command,
+        rbuffer,
+        answers.join("\0")
+    );
+
+    cache_key(abbrev.name(), &source, cwd)
+}
+
+/// Returns the abbrevs matching the word under the cursor, in config order.
+///
+/// Snippets are not generated here, so that only the chosen abbrev runs its command or script.
+fn find_candidates<'a>(
+    buffer: &str,
+    cursor: usize,
+    config: &'a Config,
+    cwd: &Path,
+) -> Vec<&'a Abbrev> {
+    let c = match find_cursor_command(buffer, cursor) {
+        Some(cursor_command) => cursor_command,
+        None => return Vec::new(),
+    };
+
+    config
         .abbrevs
         .iter()
-        .find(|abbr| abbr.is_match(command, context, last_arg, internal_args.is_empty()))?;
+        .filter(|abbr| {
+            abbr.is_match(c.command, c.context, c.last_arg, c.internal_args.is_empty())
+                && script::is_match(abbr, c.command, cwd, config)
+                // Git variables make the abbrev match only in repositories
+                && (!uses_git_variables(abbr) || Repository::discover(cwd).is_some())
+        })
+        .collect()
+}
+
+/// Returns the expansion of `abbrev` at the cursor, or `None` if its snippet cannot be made.
+fn expand<'a>(
+    buffer: &'a str,
+    cursor: usize,
+    config: &'a Config,
+    abbrev: &'a Abbrev,
+    answers: &[String],
+    template_context: &TemplateContext,
+) -> Option<ExpandResult<'a>> {
+    let cursor_command = find_cursor_command(buffer, cursor)?;
+    let CursorCommand {
+        command_index,
+        command,
+        context,
+        last_arg_index,
+        last_arg_end,
+        last_arg,
+        ..
+    } = cursor_command;
 
     let (startindex, endindex) = match abbrev.operation {
-        Operation::ReplaceSelf => {
-            let index = lbuffer.len() - last_arg.len();
-            (index, lbuffer.len())
-        }
-        Operation::ReplaceCommand => {
-            let index = lbuffer.len() - command.len();
-            (index, index + context.len())
-        }
-        Operation::ReplaceAll => {
-            let index = lbuffer.len() - command.len();
-            (index, lbuffer.len())
-        }
-        Operation::Append => {
-            let index = lbuffer.len();
-            (index, index)
-        }
-        Operation::Prepend => {
-            let index = lbuffer.len() - command.len();
-            (index, index)
+        Operation::ReplaceSelf => (last_arg_index, last_arg_end),
+        Operation::ReplaceCommand => (command_index, command_index + context.len()),
+        Operation::ReplaceAll => (command_index, last_arg_end),
+        Operation::ReplaceBuffer => (0, buffer.len()),
+        Operation::Append => (last_arg_end, last_arg_end),
+        Operation::Prepend => (command_index, command_index),
+        Operation::Wrap | Operation::Substitute => (command_index, last_arg_end),
+    };
+
+    let m = Match {
+        startindex,
+        endindex,
+        last_arg,
+        last_arg_end,
+        command,
+        abbrev,
+    };
+    let (snippet, evaluate) = expand_snippet(config, buffer, &m, &template_context.cwd)?;
+
+    let snippet = replace_cursor_placeholder(config, abbrev, snippet, CURSOR_MARK);
+    let snippet = render_abbrev_template(abbrev, snippet, evaluate, template_context)?;
+
+    // Prompts are kept until every answer is given
+    let mut prompts = parse_prompts(&snippet);
+    let snippet = if !prompts.is_empty() && prompts.len() <= answers.len() {
+        prompts.clear();
+        fill_prompts(snippet, answers, evaluate)
+    } else {
+        snippet
+    };
+
+    // A cached result of evaluation is inserted as it is, otherwise zsh stores the result
+    let mut cache = None;
+    let mut cached = false;
+    let (snippet, evaluate) = match abbrev.cache {
+        Some(ttl) if evaluate => {
+            let key = evaluated_cache_key(
+                abbrev,
+                &snippet,
+                buffer,
+                &cursor_command,
+                answers,
+                &template_context.cwd,
+            );
+
+            match Cache::open().and_then(|c| c.get(&key)) {
+                Some(value) => {
+                    cached = true;
+                    (Cow::from(value), false)
+                }
+                None => {
+                    cache = Some((key, ttl));
+                    (snippet, true)
+                }
+            }
         }
+        _ => (snippet, evaluate),
+    };
+
+    // `${N:default}` is zsh syntax in evaluated snippets, and verbatim snippets have no stops
+    let (snippet, mut stops) = if evaluate || cached || is_verbatim(abbrev) {
+        (snippet, Vec::new())
+    } else {
+        parse_tab_stops(snippet, CURSOR_MARK)
     };
 
+    let wrapped = match abbrev.operation {
+        Operation::Wrap => Some(wrapped_command(
+            buffer,
+            abbrev,
+            command_index,
+            last_arg_index,
+            last_arg_end,
+        )),
+        _ => None,
+    };
+
+    // Quoting and wrapping move the stops
+    if abbrev.quote != Quote::None || wrapped.is_some() {
+        stops.clear();
+    }
+
+    // The cursor placeholder is the final tab stop
+    if let (false, Some(index)) = (stops.is_empty(), snippet.find(CURSOR_MARK)) {
+        let offset = snippet[..index].chars().count();
+        stops.push((offset, offset));
+    }
+
     Some(ExpandResult {
-        lbuffer,
+        name: abbrev.name(),
+        buffer,
         startindex,
         endindex,
+        consumed_end: last_arg_end.max(endindex),
         last_arg,
-        snippet: &abbrev.snippet,
-        evaluate: abbrev.evaluate,
-        rbuffer,
+        snippet,
+        evaluate,
+        multiline: abbrev.multiline,
+        quote: &abbrev.quote,
+        wrapped,
+        action: &abbrev.action,
+        prompts,
+        captures: abbrev.captures(last_arg),
+        cache,
+        stops,
     })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::TempDir;
 
     fn test_config() -> Config {
         Config::load_from_str(
@@ -149,6 +535,67 @@ mod tests {
                 abbr-regex: '\.java$'
                 snippet: 'java -jar'
                 operation: prepend
+
+              - name: git commit -m
+                abbr: cm
+                snippet: commit -m '{{}}'
+                context: 'git'
+
+              - name: docker run
+                abbr: dr
+                snippet: docker run -it --name ${1:name} ${2:image} {{}}
+
+              - name: for loop
+                abbr: for
+                snippet: |-
+                  for i in {{}}; do
+                  done
+                multiline: true
+
+              - name: cd to picked directory
+                context: cd
+                abbr: f
+                snippet: $(fzf)
+                evaluate: true
+                quote: shell
+
+              - name: ls
+                abbr: l
+                snippet: ls -la
+
+              - name: less
+                abbr: l
+                snippet: less
+
+              - name: tee log
+                abbr: tee
+                global: true
+                operation: wrap
+                before: '( '
+                after: ' ) 2>&1 | tee log'
+
+              - name: podman
+                abbr: '!p'
+                global: true
+                operation: substitute
+                pattern: '^docker(\s|$)'
+                replacement: 'podman$1'
+
+              - name: commit template
+                abbr: ct
+                global: true
+                operation: replace-buffer
+                snippet: git commit -m '{{}}'
+
+              - name: git checkout -b
+                context: git
+                abbr: cob
+                snippet: checkout -b {{input:Branch name}}
+
+              - name: run tests
+                abbr: ctr
+                snippet: cargo test
+                action: accept-line
             ",
         )
         .unwrap()
@@ -162,7 +609,7 @@ mod tests {
             pub testname: &'a str,
             pub lbuffer: &'a str,
             pub rbuffer: &'a str,
-            pub expected: Option<ExpandResult<'a>>,
+            pub expected: Vec<ExpandResult<'a>>,
         }
 
         let scenarios = &[
@@ -170,165 +617,825 @@ mod tests {
                 testname: "empty",
                 lbuffer: "",
                 rbuffer: "",
-                expected: None,
+                expected: vec![],
             },
             Scenario {
                 testname: "simple abbr",
                 lbuffer: "g",
                 rbuffer: "",
-                expected: Some(ExpandResult {
-                    lbuffer: "g",
+                expected: vec![ExpandResult {
+                    name: "git",
+                    buffer: "g",
                     startindex: 0,
                     endindex: 1,
+                    consumed_end: 1,
                     last_arg: "g",
-                    snippet: "git",
+                    snippet: "git".into(),
                     evaluate: false,
-                    rbuffer: "",
-                }),
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    action: &Action::None,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
             },
             Scenario {
                 testname: "simple abbr with rbuffer",
                 lbuffer: "g",
                 rbuffer: " --pager=never",
-                expected: Some(ExpandResult {
-                    lbuffer: "g",
+                expected: vec![ExpandResult {
+                    name: "git",
+                    buffer: "g --pager=never",
                     startindex: 0,
                     endindex: 1,
+                    consumed_end: 1,
                     last_arg: "g",
-                    snippet: "git",
+                    snippet: "git".into(),
                     evaluate: false,
-                    rbuffer: " --pager=never",
-                }),
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    action: &Action::None,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
             },
             Scenario {
                 testname: "simple abbr with leading command",
                 lbuffer: "echo hello; g",
                 rbuffer: "",
-                expected: Some(ExpandResult {
-                    lbuffer: "echo hello; g",
+                expected: vec![ExpandResult {
+                    name: "git",
+                    buffer: "echo hello; g",
                     startindex: 12,
                     endindex: 13,
+                    consumed_end: 13,
                     last_arg: "g",
-                    snippet: "git",
+                    snippet: "git".into(),
                     evaluate: false,
-                    rbuffer: "",
-                }),
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    action: &Action::None,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
             },
             Scenario {
                 testname: "global abbr",
                 lbuffer: "echo hello null",
                 rbuffer: "",
-                expected: Some(ExpandResult {
-                    lbuffer: "echo hello null",
+                expected: vec![ExpandResult {
+                    name: ">/dev/null",
+                    buffer: "echo hello null",
                     startindex: 11,
                     endindex: 15,
+                    consumed_end: 15,
                     last_arg: "null",
-                    snippet: ">/dev/null",
+                    snippet: ">/dev/null".into(),
                     evaluate: false,
-                    rbuffer: "",
-                }),
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    action: &Action::None,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
             },
             Scenario {
                 testname: "global abbr with context",
                 lbuffer: "echo hello; git c",
                 rbuffer: " -m hello",
-                expected: Some(ExpandResult {
-                    lbuffer: "echo hello; git c",
+                expected: vec![ExpandResult {
+                    name: "git commit",
+                    buffer: "echo hello; git c -m hello",
                     startindex: 16,
                     endindex: 17,
+                    consumed_end: 17,
                     last_arg: "c",
-                    snippet: "commit",
+                    snippet: "commit".into(),
                     evaluate: false,
-                    rbuffer: " -m hello",
-                }),
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    action: &Action::None,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
             },
             Scenario {
                 testname: "global abbr with miss matched context",
                 lbuffer: "echo git c",
                 rbuffer: "",
-                expected: None,
+                expected: vec![],
             },
             Scenario {
                 testname: "no matched abbr",
                 lbuffer: "echo",
                 rbuffer: " hello",
-                expected: None,
+                expected: vec![],
             },
             Scenario {
                 testname: "simple abbr with evaluate=true",
                 lbuffer: "home",
                 rbuffer: "",
-                expected: Some(ExpandResult {
-                    lbuffer: "home",
+                expected: vec![ExpandResult {
+                    name: "$HOME",
+                    buffer: "home",
                     startindex: 0,
                     endindex: 4,
+                    consumed_end: 4,
                     last_arg: "home",
-                    snippet: "$HOME",
+                    snippet: "$HOME".into(),
                     evaluate: true,
-                    rbuffer: "",
-                }),
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    action: &Action::None,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
             },
             Scenario {
                 testname: "default argument abbr",
                 lbuffer: "rm",
                 rbuffer: "",
-                expected: Some(ExpandResult {
-                    lbuffer: "rm",
+                expected: vec![ExpandResult {
+                    name: "default argument",
+                    buffer: "rm",
                     startindex: 2,
                     endindex: 2,
+                    consumed_end: 2,
                     last_arg: "rm",
-                    snippet: "-i",
+                    snippet: "-i".into(),
                     evaluate: false,
-                    rbuffer: "",
-                }),
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    action: &Action::None,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
             },
             Scenario {
                 testname: "fake command abbr",
                 lbuffer: "extract test.tar",
                 rbuffer: "",
-                expected: Some(ExpandResult {
-                    lbuffer: "extract test.tar",
+                expected: vec![ExpandResult {
+                    name: "fake command",
+                    buffer: "extract test.tar",
                     startindex: 0,
                     endindex: 7,
+                    consumed_end: 16,
                     last_arg: "test.tar",
-                    snippet: "tar -xvf",
+                    snippet: "tar -xvf".into(),
                     evaluate: false,
-                    rbuffer: "",
-                }),
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    action: &Action::None,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
             },
             Scenario {
                 testname: "associated command abbr",
                 lbuffer: "test.java",
                 rbuffer: "",
-                expected: Some(ExpandResult {
-                    lbuffer: "test.java",
+                expected: vec![ExpandResult {
+                    name: "associated command",
+                    buffer: "test.java",
                     startindex: 0,
                     endindex: 0,
+                    consumed_end: 9,
                     last_arg: "test.java",
-                    snippet: "java -jar",
+                    snippet: "java -jar".into(),
+                    evaluate: false,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    action: &Action::None,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
+            },
+            Scenario {
+                testname: "cursor placeholder",
+                lbuffer: "git cm",
+                rbuffer: "",
+                expected: vec![ExpandResult {
+                    name: "git commit -m",
+                    buffer: "git cm",
+                    startindex: 4,
+                    endindex: 6,
+                    consumed_end: 6,
+                    last_arg: "cm",
+                    snippet: "commit -m '\x1f'".into(),
+                    evaluate: false,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    action: &Action::None,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
+            },
+            Scenario {
+                testname: "tab stops",
+                lbuffer: "dr",
+                rbuffer: "",
+                expected: vec![ExpandResult {
+                    name: "docker run",
+                    buffer: "dr",
+                    startindex: 0,
+                    endindex: 2,
+                    consumed_end: 2,
+                    last_arg: "dr",
+                    snippet: "docker run -it --name name image \x1f".into(),
+                    evaluate: false,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    action: &Action::None,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![(22, 26), (27, 32), (33, 33)],
+                }],
+            },
+            Scenario {
+                testname: "multiline snippet",
+                lbuffer: "for",
+                rbuffer: "",
+                expected: vec![ExpandResult {
+                    name: "for loop",
+                    buffer: "for",
+                    startindex: 0,
+                    endindex: 3,
+                    consumed_end: 3,
+                    last_arg: "for",
+                    snippet: "for i in \x1f; do\ndone".into(),
                     evaluate: false,
-                    rbuffer: "",
-                }),
+                    multiline: true,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    action: &Action::None,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
+            },
+            Scenario {
+                testname: "quoted snippet",
+                lbuffer: "cd f",
+                rbuffer: "",
+                expected: vec![ExpandResult {
+                    name: "cd to picked directory",
+                    buffer: "cd f",
+                    startindex: 3,
+                    endindex: 4,
+                    consumed_end: 4,
+                    last_arg: "f",
+                    snippet: "$(fzf)".into(),
+                    evaluate: true,
+                    multiline: false,
+                    quote: &Quote::Shell,
+                    wrapped: None,
+                    action: &Action::None,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
+            },
+            Scenario {
+                testname: "wrap the command",
+                lbuffer: "echo; make -j4 tee",
+                rbuffer: "",
+                expected: vec![ExpandResult {
+                    name: "tee log",
+                    buffer: "echo; make -j4 tee",
+                    startindex: 6,
+                    endindex: 18,
+                    consumed_end: 18,
+                    last_arg: "tee",
+                    snippet: "( \x1e ) 2>&1 | tee log".into(),
+                    evaluate: false,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: Some("make -j4"),
+                    action: &Action::None,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
+            },
+            Scenario {
+                testname: "substitute in the command",
+                lbuffer: "echo; docker run -it !p",
+                rbuffer: "",
+                expected: vec![ExpandResult {
+                    name: "podman",
+                    buffer: "echo; docker run -it !p",
+                    startindex: 6,
+                    endindex: 23,
+                    consumed_end: 23,
+                    last_arg: "!p",
+                    snippet: "podman run -it".into(),
+                    evaluate: false,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    action: &Action::None,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
+            },
+            Scenario {
+                testname: "substitute without a match",
+                lbuffer: "podman ps !p",
+                rbuffer: "",
+                expected: vec![],
+            },
+            Scenario {
+                testname: "replace the whole buffer",
+                lbuffer: "echo a; ct",
+                rbuffer: " && ls",
+                expected: vec![ExpandResult {
+                    name: "commit template",
+                    buffer: "echo a; ct && ls",
+                    startindex: 0,
+                    endindex: 16,
+                    consumed_end: 16,
+                    last_arg: "ct",
+                    snippet: "git commit -m '\x1f'".into(),
+                    evaluate: false,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    action: &Action::None,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
+            },
+            Scenario {
+                testname: "action after expansion",
+                lbuffer: "ctr",
+                rbuffer: "",
+                expected: vec![ExpandResult {
+                    name: "run tests",
+                    buffer: "ctr",
+                    startindex: 0,
+                    endindex: 3,
+                    consumed_end: 3,
+                    last_arg: "ctr",
+                    snippet: "cargo test".into(),
+                    evaluate: false,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    action: &Action::AcceptLine,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
+            },
+            Scenario {
+                testname: "prompts",
+                lbuffer: "git cob",
+                rbuffer: "",
+                expected: vec![ExpandResult {
+                    name: "git checkout -b",
+                    buffer: "git cob",
+                    startindex: 4,
+                    endindex: 7,
+                    consumed_end: 7,
+                    last_arg: "cob",
+                    snippet: "checkout -b {{input:Branch name}}".into(),
+                    evaluate: false,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    action: &Action::None,
+                    prompts: vec![Prompt::Input("Branch name".to_string())],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
+            },
+            Scenario {
+                testname: "multiple candidates",
+                lbuffer: "l",
+                rbuffer: "",
+                expected: vec![
+                    ExpandResult {
+                        name: "ls",
+                        buffer: "l",
+                        startindex: 0,
+                        endindex: 1,
+                        consumed_end: 1,
+                        last_arg: "l",
+                        snippet: "ls -la".into(),
+                        evaluate: false,
+                        multiline: false,
+                        quote: &Quote::None,
+                        wrapped: None,
+                        action: &Action::None,
+                        prompts: vec![],
+                        captures: vec![],
+                        cache: None,
+                        stops: vec![],
+                    },
+                    ExpandResult {
+                        name: "less",
+                        buffer: "l",
+                        startindex: 0,
+                        endindex: 1,
+                        consumed_end: 1,
+                        last_arg: "l",
+                        snippet: "less".into(),
+                        evaluate: false,
+                        multiline: false,
+                        quote: &Quote::None,
+                        wrapped: None,
+                        action: &Action::None,
+                        prompts: vec![],
+                        captures: vec![],
+                        cache: None,
+                        stops: vec![],
+                    },
+                ],
+            },
+            Scenario {
+                testname: "cursor inside a longer word",
+                lbuffer: "g",
+                rbuffer: "it --version",
+                expected: vec![],
+            },
+            Scenario {
+                testname: "cursor inside abbr",
+                lbuffer: "ho",
+                rbuffer: "me; ls",
+                expected: vec![ExpandResult {
+                    name: "$HOME",
+                    buffer: "home; ls",
+                    startindex: 0,
+                    endindex: 4,
+                    consumed_end: 4,
+                    last_arg: "home",
+                    snippet: "$HOME".into(),
+                    evaluate: true,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    action: &Action::None,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
+            },
+            Scenario {
+                testname: "cursor inside abbr with operation",
+                lbuffer: "echo; extract test.t",
+                rbuffer: "ar -C /tmp",
+                expected: vec![ExpandResult {
+                    name: "fake command",
+                    buffer: "echo; extract test.tar -C /tmp",
+                    startindex: 6,
+                    endindex: 13,
+                    consumed_end: 22,
+                    last_arg: "test.tar",
+                    snippet: "tar -xvf".into(),
+                    evaluate: false,
+                    multiline: false,
+                    quote: &Quote::None,
+                    wrapped: None,
+                    action: &Action::None,
+                    prompts: vec![],
+                    captures: vec![],
+                    cache: None,
+                    stops: vec![],
+                }],
+            },
+            Scenario {
+                testname: "cursor before a word",
+                lbuffer: "echo ",
+                rbuffer: "null",
+                expected: vec![],
             },
         ];
 
         for s in scenarios {
-            let args = ExpandArgs {
-                lbuffer: s.lbuffer.to_string(),
-                rbuffer: s.rbuffer.to_string(),
-            };
-
-            let actual = expand(&args, &config);
+            let buffer = format!("{}{}", s.lbuffer, s.rbuffer);
+            let context = TemplateContext::default();
+            let actual = find_candidates(&buffer, s.lbuffer.len(), &config, &context.cwd)
+                .into_iter()
+                .filter_map(|abbrev| {
+                    expand(&buffer, s.lbuffer.len(), &config, abbrev, &[], &context)
+                })
+                .collect::<Vec<_>>();
 
             assert_eq!(actual, s.expected, "{}", s.testname);
         }
     }
+
+    #[test]
+    fn test_evaluated_cache_key() {
+        let config = test_config();
+        let abbrev = &config.abbrevs[3];
+        let key = |lbuffer: &str, rbuffer: &str| {
+            let buffer = format!("{}{}", lbuffer, rbuffer);
+            let c = find_cursor_command(&buffer, lbuffer.len()).unwrap();
+            evaluated_cache_key(abbrev, "$HOME", &buffer, &c, &[], Path::new("/"))
+        };
+
+        // Evaluated snippets can read the whole command line
+        assert_eq!(key("echo home", ""), key("echo home", ""));
+        assert_ne!(key("echo home", ""), key("ls home", ""));
+        assert_ne!(key("echo home", ""), key("echo home", " | less"));
+    }
+
+    #[test]
+    fn test_set_locals() {
+        let config = Config::load_from_str(
+            r"
+            abbrevs:
+              - name: checkout remote branch
+                context: git
+                global: true
+                abbr-regex: '^@(\w+)/(\w+)$'
+                snippet: checkout -t $ZABBREV_WORD
+                evaluate: true
+            ",
+        )
+        .unwrap();
+
+        struct Scenario<'a> {
+            pub testname: &'a str,
+            pub buffer: &'a str,
+            pub cursor: usize,
+            pub expected: &'a str,
+        }
+
+        let scenarios = &[
+            Scenario {
+                testname: "args, captures and the rest of the buffer",
+                buffer: "git -C repo @origin/main | less",
+                cursor: 24,
+                expected: concat!(
+                    "local ZABBREV_CONTEXT=git ZABBREV_COMMAND='git -C repo @origin/main' ",
+                    "ZABBREV_WORD='@origin/main' ZABBREV_RBUFFER=' | less';",
+                    "local -a zabbrev_args=(-C repo '@origin/main') zabbrev_match=(origin main);",
+                ),
+            },
+            Scenario {
+                testname: "later command",
+                buffer: "cd repo; git @upstream/dev",
+                cursor: 26,
+                expected: concat!(
+                    "local ZABBREV_CONTEXT=git ZABBREV_COMMAND='git @upstream/dev' ",
+                    "ZABBREV_WORD='@upstream/dev' ZABBREV_RBUFFER='';",
+                    "local -a zabbrev_args=('@upstream/dev') zabbrev_match=(upstream dev);",
+                ),
+            },
+        ];
+
+        for s in scenarios {
+            let context = TemplateContext::default();
+
+            // expand gives the command line at the cursor
+            let c = find_cursor_command(s.buffer, s.cursor).unwrap();
+            let abbrev = find_candidates(s.buffer, s.cursor, &config, Path::new("/"))[0];
+            let result = expand(s.buffer, s.cursor, &config, abbrev, &[], &context).unwrap();
+            assert_eq!(
+                set_locals(s.buffer, &c, &result.captures),
+                s.expected,
+                "{}",
+                s.testname
+            );
+
+            // expand-line gives the one around each trigger
+            let replacements = line::expand_line(s.buffer, &config, &context);
+            let code = line::replacement_code(s.buffer, &replacements);
+            assert!(code.contains(s.expected), "{}: {}", s.testname, code);
+        }
+    }
+
+    #[test]
+    fn test_find_candidates() {
+        let config = Config::load_from_str(
+            r"
+            abbrevs:
+              - name: failing command
+                abbr: l
+                snippet-command: ['false']
+
+              - name: ls
+                abbr: l
+                snippet: ls -la
+
+              - name: log branch
+                abbr: l
+                operation: wrap
+                after: ' | tee {{git.branch}}.log'
+            ",
+        )
+        .unwrap();
+        let candidates = find_candidates("l", 1, &config, Path::new("/"));
+
+        // Commands are only run for the chosen candidate
+        assert_eq!(
+            candidates.iter().map(|a| a.name()).collect::<Vec<_>>(),
+            vec!["failing command", "ls"]
+        );
+        assert_eq!(
+            expand(
+                "l",
+                1,
+                &config,
+                candidates[1],
+                &[],
+                &TemplateContext::default()
+            )
+            .map(|result| result.snippet),
+            Some(Cow::from("ls -la"))
+        );
+
+        // Git variables in `before` or `after` need a repository too
+        let dir = TempDir::new("candidates");
+        dir.write(".git/HEAD", "ref: refs/heads/main\n");
+        assert_eq!(
+            find_candidates("l", 1, &config, &dir)
+                .iter()
+                .map(|a| a.name())
+                .collect::<Vec<_>>(),
+            vec!["failing command", "ls", "log branch"]
+        );
+    }
+}
+
+/// Returns the zsh code setting the local variables which give the command line at `c` to
+/// evaluated snippets.
+fn set_locals(buffer: &str, c: &CursorCommand, captures: &[&str]) -> String {
+    let words = |words: &mut dyn Iterator<Item = &str>| {
+        words
+            .map(|word| escape(Cow::from(word)).into_owned())
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let full_command = &buffer[c.command_index..c.last_arg_end];
+
+    format!(
+        concat!(
+            "local ZABBREV_CONTEXT={} ZABBREV_COMMAND={} ZABBREV_WORD={} ZABBREV_RBUFFER={};",
+            "local -a zabbrev_args=({}) zabbrev_match=({});",
+        ),
+        escape(Cow::from(c.context)),
+        escape(Cow::from(full_command)),
+        escape(Cow::from(c.last_arg)),
+        escape(Cow::from(&buffer[c.last_arg_end..])),
+        words(&mut full_command.split_whitespace().skip(1)),
+        words(&mut captures.iter().copied()),
+    )
+}
+
+/// Returns the zsh code storing the evaluated `$snippet` in the cache, if it is to be cached.
+fn store_cache(cache: &Option<(String, Duration)>, name: &str) -> String {
+    match cache {
+        Some((key, ttl)) => format!(
+            r#"zabbrev cache --store={} --name={} --ttl={}ms --value="$snippet";"#,
+            key,
+            escape(Cow::from(name)),
+            ttl.as_millis()
+        ),
+        None => String::new(),
+    }
+}
+
+/// Returns the zsh expression quoting the parameter `name` according to `quote`.
+fn quote_expression(quote: &Quote, name: &str) -> String {
+    let expression = match quote {
+        Quote::None => format!("${}", name),
+        Quote::Shell => format!("${{(q){}}}", name),
+        Quote::Single => format!("${{(qq){}}}", name),
+        Quote::Double => format!("${{(qqq){}}}", name),
+        Quote::PerLine => format!("${{(j: :)${{(@q)${{(@f){}}}}}}}", name),
+    };
+
+    // An empty string stays empty instead of becoming ''
+    format!("${{{}:+{}}}", name, expression)
+}
+
+/// Returns whether the snippet of `abbrev` is the typed command or generated by a command or script,
+/// which is inserted without rendering templates or tab stops.
+fn is_verbatim(abbrev: &Abbrev) -> bool {
+    abbrev.operation == Operation::Substitute
+        || abbrev.snippet_command.is_some()
+        || script::defines_snippet(abbrev)
+}
+
+/// Returns whether the template rendered for `abbrev` uses git variables.
+///
+/// `wrap` renders `before` and `after`, and the others render `snippet`.
+fn uses_git_variables(abbrev: &Abbrev) -> bool {
+    !is_verbatim(abbrev)
+        && [&abbrev.snippet, &abbrev.before, &abbrev.after]
+            .iter()
+            .any(|text| requires_repository(text))
+}
+
+/// Renders the template in the snippet of `abbrev`, printing an error if it fails.
+fn render_abbrev_template<'a>(
+    abbrev: &Abbrev,
+    snippet: Cow<'a, str>,
+    evaluate: bool,
+    context: &TemplateContext,
+) -> Option<Cow<'a, str>> {
+    if is_verbatim(abbrev) {
+        return Some(snippet);
+    }
+
+    match render_template(snippet, context, evaluate) {
+        Ok(snippet) => Some(snippet),
+        // Snippets inlined by recursive expansion may use git variables too, and are silently
+        // skipped outside repositories like the abbrevs using them
+        Err(TemplateError::NotInRepository) => None,
+        Err(err) => {
+            let error_message = format!("invalid template in abbrev '{}': {}", abbrev.name(), err);
+            let error_style = Color::Red.normal();
+
+            eprintln!("{}", error_style.paint(error_message));
+            None
+        }
+    }
+}
+
+/// Replaces the first cursor placeholder of `abbrev` in `snippet` with `to`.
+fn replace_cursor_placeholder<'a>(
+    config: &Config,
+    abbrev: &Abbrev,
+    snippet: Cow<'a, str>,
+    to: &str,
+) -> Cow<'a, str> {
+    let placeholder = abbrev.cursor.as_ref().unwrap_or(&config.cursor);
+
+    if !placeholder.is_empty() && snippet.contains(placeholder.as_str()) {
+        Cow::from(snippet.replacen(placeholder.as_str(), to, 1))
+    } else {
+        snippet
+    }
+}
+
+/// Returns the command enclosed by a `wrap` abbrev, which loses its trigger unless `keep-trigger` is set.
+fn wrapped_command<'a>(
+    buffer: &'a str,
+    abbrev: &Abbrev,
+    command_index: usize,
+    trigger_index: usize,
+    trigger_end: usize,
+) -> &'a str {
+    if abbrev.keep_trigger {
+        &buffer[command_index..trigger_end]
+    } else {
+        buffer[command_index..trigger_index].trim_end()
+    }
+}
+
+fn is_command_separator(c: char) -> bool {
+    matches!(c, ';' | '&' | '|' | '(' | '`' | '\n')
 }
 
 fn find_last_command_index(line: &str) -> usize {
-    line.rfind(|c| matches!(c, ';' | '&' | '|' | '(' | '`' | '\n'))
-        .map(|i| i + 1)
-        .unwrap_or(0)
+    line.rfind(is_command_separator).map(|i| i + 1).unwrap_or(0)
 }
 
 #[test]
@@ -338,3 +1445,13 @@ fn test_find_last_command_index() {
     assert_eq!(find_last_command_index("echo hello && git commit"), 13);
     assert_eq!(find_last_command_index("seq 10 | tail -3 | cat"), 18);
 }
+
+#[test]
+fn test_quote_expression() {
+    assert_eq!(quote_expression(&Quote::None, "s"), "${s:+$s}");
+    assert_eq!(quote_expression(&Quote::Shell, "s"), "${s:+${(q)s}}");
+    assert_eq!(
+        quote_expression(&Quote::PerLine, "s"),
+        "${s:+${(j: :)${(@q)${(@f)s}}}}"
+    );
+}
@@ -0,0 +1,94 @@
+use regex::Regex;
+use std::borrow::Cow;
+
+/// Removes the tab stops `${N}` and `${N:default}` from `snippet`, leaving their defaults.
+///
+/// Returns the stops as character ranges in the resulting text, ordered by `N`.
+/// Characters in `ignore` are not counted, as zsh removes them before the stops are used.
+pub fn parse_tab_stops<'a>(
+    snippet: Cow<'a, str>,
+    ignore: &str,
+) -> (Cow<'a, str>, Vec<(usize, usize)>) {
+    let pattern = Regex::new(r"\$\{(\d+)(?::([^}]*))?\}").unwrap();
+
+    if !pattern.is_match(&snippet) {
+        return (snippet, Vec::new());
+    }
+
+    let count_chars = |s: &str| s.chars().filter(|c| !ignore.contains(*c)).count();
+
+    let mut text = String::new();
+    let mut stops = Vec::new();
+    let mut offset = 0;
+    let mut index = 0;
+
+    for captures in pattern.captures_iter(&snippet) {
+        let whole = captures.get(0).unwrap();
+        let number: usize = captures[1].parse().unwrap_or(usize::MAX);
+        let default = captures.get(2).map_or("", |m| m.as_str());
+
+        let before = &snippet[index..whole.start()];
+        offset += count_chars(before);
+        text.push_str(before);
+
+        let start = offset;
+        offset += count_chars(default);
+        text.push_str(default);
+
+        stops.push((number, start, offset));
+        index = whole.end();
+    }
+
+    text.push_str(&snippet[index..]);
+    stops.sort_by_key(|&(number, _, _)| number);
+
+    let stops = stops
+        .into_iter()
+        .map(|(_, start, end)| (start, end))
+        .collect();
+
+    (Cow::from(text), stops)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tab_stops() {
+        struct Scenario {
+            pub testname: &'static str,
+            pub snippet: &'static str,
+            pub expected: (&'static str, Vec<(usize, usize)>),
+        }
+
+        let scenarios = [
+            Scenario {
+                testname: "no tab stops",
+                snippet: "docker run -it",
+                expected: ("docker run -it", vec![]),
+            },
+            Scenario {
+                testname: "tab stops with defaults",
+                snippet: "docker run -it --name ${1:name} ${2:image}",
+                expected: ("docker run -it --name name image", vec![(22, 26), (27, 32)]),
+            },
+            Scenario {
+                testname: "tab stops ordered by number",
+                snippet: "cp ${2} ${1:src}",
+                expected: ("cp  src", vec![(4, 7), (3, 3)]),
+            },
+            Scenario {
+                testname: "multibyte characters and ignored characters",
+                snippet: "echo '\x1fあ' ${1:い}",
+                expected: ("echo '\x1fあ' い", vec![(9, 10)]),
+            },
+        ];
+
+        for s in &scenarios {
+            let (text, stops) = parse_tab_stops(Cow::from(s.snippet), "\x1f");
+
+            assert_eq!((text.as_ref(), stops), s.expected, "{}", s.testname);
+        }
+    }
+}
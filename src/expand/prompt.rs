@@ -0,0 +1,88 @@
+use regex::{Captures, Regex};
+use std::borrow::Cow;
+
+#[derive(Debug, PartialEq)]
+pub enum Prompt {
+    /// `{{input:Label}}` asks for a line of text
+    Input(String),
+    /// `{{choice:a|b|c}}` asks to pick one of the options
+    Choice(Vec<String>),
+}
+
+impl Prompt {
+    /// Returns the answer used when nobody is asked, as in `expand-line`.
+    pub fn default_answer(&self) -> &str {
+        match self {
+            Prompt::Input(_) => "",
+            Prompt::Choice(options) => options.first().map_or("", String::as_str),
+        }
+    }
+}
+
+fn prompt_pattern() -> Regex {
+    Regex::new(r"\{\{(input|choice):([^}]*)\}\}").unwrap()
+}
+
+/// Returns the prompts in `snippet` in order of appearance.
+pub fn parse_prompts(snippet: &str) -> Vec<Prompt> {
+    prompt_pattern()
+        .captures_iter(snippet)
+        .map(|captures| match &captures[1] {
+            "input" => Prompt::Input(captures[2].to_string()),
+            _ => Prompt::Choice(captures[2].split('|').map(str::to_string).collect()),
+        })
+        .collect()
+}
+
+/// Replaces the prompts in `snippet` with `answers`.
+///
+/// Evaluated snippets refer to `$zabbrev_answers` instead, so that answers are not evaluated.
+pub fn fill_prompts<'a, S: AsRef<str>>(
+    snippet: Cow<'a, str>,
+    answers: &[S],
+    evaluate: bool,
+) -> Cow<'a, str> {
+    let pattern = prompt_pattern();
+
+    if !pattern.is_match(&snippet) {
+        return snippet;
+    }
+
+    let mut index = 0;
+    let filled = pattern.replace_all(&snippet, |_: &Captures| {
+        index += 1;
+        if evaluate {
+            format!("${{zabbrev_answers[{}]}}", index)
+        } else {
+            answers.get(index - 1).map_or("", AsRef::as_ref).to_string()
+        }
+    });
+
+    Cow::from(filled.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prompts() {
+        let snippet = "kubectl --context {{choice:dev|prod}} label {{input:Label}}";
+
+        assert_eq!(
+            parse_prompts(snippet),
+            vec![
+                Prompt::Choice(vec!["dev".to_string(), "prod".to_string()]),
+                Prompt::Input("Label".to_string()),
+            ]
+        );
+        assert_eq!(
+            fill_prompts(Cow::from(snippet), &["prod", "a=b"], false),
+            "kubectl --context prod label a=b"
+        );
+        assert_eq!(
+            fill_prompts(Cow::from(snippet), &["prod", "a=b"], true),
+            "kubectl --context ${zabbrev_answers[1]} label ${zabbrev_answers[2]}"
+        );
+    }
+}
@@ -0,0 +1,396 @@
+use super::command::run_command;
+use super::line::{find_matches, Match};
+use super::script::{generate_snippet, print_error};
+use super::{is_verbatim, wrapped_command, WRAP_MARK};
+use crate::cache::{cache_key, Cache};
+use crate::config::abbrev::Operation;
+use crate::config::{Abbrev, Config};
+use ansi_term::Color;
+use regex::{Captures, Regex};
+use std::borrow::Cow;
+use std::path::Path;
+use thiserror::Error;
+
+static MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, Error, PartialEq)]
+pub enum RecursionError {
+    #[error("cycle detected in recursive expansion: {0}")]
+    Cycle(String),
+
+    #[error("recursive expansion exceeded depth {}: {0}", MAX_DEPTH)]
+    TooDeep(String),
+}
+
+/// Returns the snippet of `m` in `cwd` and whether it should be evaluated.
+///
+/// If the abbrev is recursive, abbrevs in the produced command are expanded
+/// until nothing changes. Snippets are inlined as written, and if any of them
+/// has `evaluate: true`, the others are escaped so that only it is evaluated.
+pub fn expand_snippet<'a>(
+    config: &'a Config,
+    buffer: &str,
+    m: &Match<'a, '_>,
+    cwd: &Path,
+) -> Option<(Cow<'a, str>, bool)> {
+    let abbrev = m.abbrev;
+
+    if abbrev.operation == Operation::Wrap {
+        let snippet = format!("{}{}{}", abbrev.before, WRAP_MARK, abbrev.after);
+        return Some((Cow::from(snippet), abbrev.evaluate));
+    }
+
+    if abbrev.operation == Operation::Substitute {
+        let trigger_index = m.endindex - m.last_arg.len();
+        let command = wrapped_command(buffer, abbrev, m.startindex, trigger_index, m.endindex);
+        let snippet = abbrev.substitute(command)?.into_owned();
+
+        // The typed command is never evaluated
+        return Some((Cow::from(snippet), false));
+    }
+
+    if let Some(argv) = &abbrev.snippet_command {
+        let timeout = abbrev.timeout.unwrap_or(config.timeout);
+        let cache = abbrev.cache.and_then(|ttl| {
+            let key = cache_key(abbrev.name(), &argv.join("\0"), cwd);
+            Some((Cache::open()?, key, ttl))
+        });
+
+        if let Some(value) = cache.as_ref().and_then(|(cache, key, _)| cache.get(key)) {
+            return Some((Cow::from(value), false));
+        }
+
+        return match run_command(argv, timeout) {
+            // The output is never evaluated either
+            Ok(output) => {
+                if let Some((cache, key, ttl)) = &cache {
+                    let _ = cache.set(key, abbrev.name(), *ttl, &output);
+                }
+                Some((Cow::from(output), false))
+            }
+            Err(err) => {
+                let error_message =
+                    format!("command in abbrev '{}' failed: {}", abbrev.name(), err);
+                let error_style = Color::Red.normal();
+
+                eprintln!("{}", error_style.paint(error_message));
+                None
+            }
+        };
+    }
+
+    if let Some(result) = generate_snippet(abbrev, m.command, cwd, config) {
+        // Generated snippets are inserted as they are
+        return match result {
+            Ok(snippet) => Some((Cow::from(snippet), false)),
+            Err(err) => {
+                print_error(abbrev, &err);
+                None
+            }
+        };
+    }
+
+    if !abbrev.recursive.unwrap_or(config.recursive) {
+        return Some((Cow::from(&abbrev.snippet), abbrev.evaluate));
+    }
+
+    match expand_recursively(config, buffer, m, cwd, &mut Vec::new()) {
+        Ok((snippet, evaluate)) => Some((Cow::from(snippet), evaluate)),
+        Err(err) => {
+            let error_style = Color::Red.normal();
+
+            eprintln!("{}", error_style.paint(err.to_string()));
+            None
+        }
+    }
+}
+
+fn expand_recursively<'a>(
+    config: &'a Config,
+    buffer: &str,
+    m: &Match<'a, '_>,
+    cwd: &Path,
+    chain: &mut Vec<&'a Abbrev>,
+) -> Result<(String, bool), RecursionError> {
+    let abbrev = m.abbrev;
+    let is_cycle = chain.iter().any(|&a| std::ptr::eq(a, abbrev));
+
+    chain.push(abbrev);
+
+    if is_cycle {
+        return Err(RecursionError::Cycle(chain_to_string(chain)));
+    }
+    if chain.len() > MAX_DEPTH {
+        return Err(RecursionError::TooDeep(chain_to_string(chain)));
+    }
+
+    let snippet = &abbrev.snippet;
+    let mut produced = format!(
+        "{}{}{}",
+        &buffer[..m.startindex],
+        snippet,
+        &buffer[m.endindex..]
+    );
+    let mut index = m.startindex;
+    let mut endindex = m.startindex + snippet.len();
+
+    // Each snippet is evaluated or not on its own, so the text is kept in segments
+    let mut segments = Vec::new();
+
+    // Re-run matching after every expansion so that later words see the expanded context
+    // Abbrevs rewriting the whole command or buffer, or generating snippets, are never inlined,
+    // and an abbrev does not expand its own trigger in its snippet, like `ls` to `ls --color`
+    while let Some(inner) = find_matches(&produced, config, index..endindex, cwd)
+        .into_iter()
+        .find(|inner| {
+            let rewrites = matches!(
+                inner.abbrev.operation,
+                Operation::ReplaceBuffer | Operation::Wrap | Operation::Substitute
+            );
+            !rewrites && !is_verbatim(inner.abbrev) && !std::ptr::eq(inner.abbrev, abbrev)
+        })
+    {
+        let (inner_snippet, inner_evaluate) =
+            expand_recursively(config, &produced, &inner, cwd, chain)?;
+
+        // `$1` of an inlined snippet is its own trigger
+        let inner_snippet = if inner_evaluate {
+            bind_trigger(&inner_snippet, inner.last_arg)
+        } else {
+            inner_snippet
+        };
+
+        let (joint_append, joint_prepend) = match inner.abbrev.operation {
+            Operation::Append => (" ", ""),
+            Operation::Prepend => ("", " "),
+            _ => ("", ""),
+        };
+        let inserted = format!("{}{}{}", joint_append, inner_snippet, joint_prepend);
+
+        segments.push((
+            produced[index..inner.startindex].to_string(),
+            abbrev.evaluate,
+        ));
+        segments.push((joint_append.to_string(), false));
+        segments.push((inner_snippet, inner_evaluate));
+        segments.push((joint_prepend.to_string(), false));
+
+        endindex = endindex - (inner.endindex - inner.startindex) + inserted.len();
+        index = inner.startindex + inserted.len();
+
+        produced = format!(
+            "{}{}{}",
+            &produced[..inner.startindex],
+            inserted,
+            &produced[inner.endindex..]
+        );
+    }
+
+    segments.push((produced[index..endindex].to_string(), abbrev.evaluate));
+    chain.pop();
+
+    // Text not to be evaluated is escaped if any other text is
+    let evaluate = segments.iter().any(|&(_, evaluate)| evaluate);
+    let expanded = segments
+        .into_iter()
+        .map(|(text, evaluated)| {
+            if evaluate && !evaluated {
+                escape_for_evaluation(&text)
+            } else {
+                text
+            }
+        })
+        .collect();
+
+    Ok((expanded, evaluate))
+}
+
+/// Escapes `text` so that `(e)` expansion leaves it as it is.
+pub fn escape_for_evaluation(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        if matches!(c, '\\' | '$' | '`') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Replaces `$1` in an evaluated snippet with `trigger`.
+fn bind_trigger(snippet: &str, trigger: &str) -> String {
+    let pattern = Regex::new(r"\\.|\$\{1\}|\$1[0-9]*").unwrap();
+
+    pattern
+        .replace_all(snippet, |captures: &Captures| match &captures[0] {
+            "$1" | "${1}" => escape_for_evaluation(trigger),
+            // Escaped characters and `$10` and so on are kept
+            other => other.to_string(),
+        })
+        .into_owned()
+}
+
+fn chain_to_string(chain: &[&Abbrev]) -> String {
+    chain
+        .iter()
+        .map(|abbrev| abbrev.name())
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expand::template::current_dir;
+
+    fn test_config() -> Config {
+        // n1 -> n2 -> ... -> n18, one deeper than allowed
+        let chain = (1..=17)
+            .map(|i| {
+                let abbrev = format!(
+                    "{{ name: n{0}, abbr: n{0}, snippet: n{1}, recursive: true }}",
+                    i,
+                    i + 1
+                );
+                format!("\n              - {}", abbrev)
+            })
+            .collect::<String>();
+
+        Config::load_from_str(&format!(
+            r"
+            abbrevs:
+              - name: git
+                abbr: g
+                snippet: git
+
+              - name: git commit
+                abbr: c
+                snippet: commit
+                context: 'git'
+
+              - name: git commit --amend
+                abbr: gca
+                snippet: g c --amend
+                recursive: true
+
+              - name: default argument
+                abbr: rm
+                snippet: -i
+                operation: append
+
+              - name: remove all
+                abbr: rmall
+                snippet: rm -r $1
+                evaluate: true
+                recursive: true
+
+              - name: a
+                abbr: a
+                snippet: b
+                recursive: true
+
+              - name: b
+                abbr: b
+                snippet: a
+
+              - name: ls
+                abbr: ls
+                snippet: ls --color
+                recursive: true
+
+              - name: home
+                abbr: home
+                snippet: echo $HOME here
+                recursive: true
+
+              - name: here
+                abbr: here
+                snippet: $(pwd) $1
+                evaluate: true
+                global: true
+            {}",
+            chain
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_expand_recursively() {
+        let config = test_config();
+
+        struct Scenario<'a> {
+            pub testname: &'a str,
+            pub buffer: &'a str,
+            pub expected: Result<(&'a str, bool), RecursionError>,
+        }
+
+        let scenarios = &[
+            Scenario {
+                testname: "expand abbrevs in snippet",
+                buffer: "gca",
+                expected: Ok(("git commit --amend", false)),
+            },
+            Scenario {
+                testname: "expand with context of the command",
+                buffer: "echo; gca -v",
+                expected: Ok(("git commit --amend", false)),
+            },
+            Scenario {
+                testname: "evaluate if any snippet is evaluated",
+                buffer: "rmall",
+                expected: Ok(("rm -i -r $1", true)),
+            },
+            Scenario {
+                testname: "own trigger in snippet",
+                buffer: "ls",
+                expected: Ok(("ls --color", false)),
+            },
+            Scenario {
+                testname: "evaluate only evaluated snippets",
+                buffer: "home",
+                expected: Ok((r"echo \$HOME $(pwd) here", true)),
+            },
+            Scenario {
+                testname: "cycle",
+                buffer: "a",
+                expected: Err(RecursionError::Cycle("a -> b -> a".to_string())),
+            },
+            Scenario {
+                testname: "too deep",
+                buffer: "n1",
+                expected: Err(RecursionError::TooDeep(
+                    (1..=17)
+                        .map(|i| format!("n{}", i))
+                        .collect::<Vec<_>>()
+                        .join(" -> "),
+                )),
+            },
+        ];
+
+        for s in scenarios {
+            let cwd = current_dir();
+            let m = &find_matches(s.buffer, &config, 0..s.buffer.len(), &cwd)[0];
+            let actual = expand_recursively(&config, s.buffer, m, &cwd, &mut Vec::new());
+            let actual = actual
+                .as_ref()
+                .map(|(snippet, evaluate)| (snippet.as_str(), *evaluate))
+                .map_err(Clone::clone);
+
+            assert_eq!(actual, s.expected, "{}", s.testname);
+        }
+    }
+
+    #[test]
+    fn test_expand_snippet_non_recursive() {
+        let config = test_config();
+        let cwd = current_dir();
+        let m = &find_matches("b", &config, 0..1, &cwd)[0];
+
+        assert_eq!(
+            expand_snippet(&config, "b", m, &cwd),
+            Some((Cow::from("a"), false))
+        );
+    }
+}
@@ -0,0 +1,359 @@
+//! Scripts deciding whether an abbrev matches and generating its snippet.
+//!
+//! Scripts are written in Rhai and available with the `script` feature.
+
+use crate::config::{Abbrev, Config};
+use ansi_term::Color;
+use std::path::Path;
+#[cfg(not(feature = "script"))]
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+#[cfg_attr(not(feature = "script"), allow(dead_code))]
+pub enum ScriptError {
+    #[cfg(not(feature = "script"))]
+    #[error("zabbrev is built without the `script' feature")]
+    Unsupported,
+
+    #[error("{0}")]
+    Compile(String),
+
+    #[error("`{0}' failed: {1}")]
+    Runtime(String, String),
+
+    #[error("`{0}' timed out after {1}ms")]
+    Timeout(String, u128),
+}
+
+/// Returns whether the `matches(ctx)` function of the script accepts `command` run in `cwd`.
+///
+/// Abbrevs without a script or without `matches` always match.
+pub fn is_match(abbrev: &Abbrev, command: &str, cwd: &Path, config: &Config) -> bool {
+    let script = match &abbrev.script {
+        Some(script) => script,
+        None => return true,
+    };
+    let timeout = abbrev.timeout.unwrap_or(config.timeout);
+
+    match call::<bool>(script, "matches", command, cwd, timeout) {
+        Ok(matches) => matches.unwrap_or(true),
+        Err(err) => {
+            print_error(abbrev, &err);
+            false
+        }
+    }
+}
+
+/// Returns the snippet generated by the `snippet(ctx)` function of the script, if it has one.
+pub fn generate_snippet(
+    abbrev: &Abbrev,
+    command: &str,
+    cwd: &Path,
+    config: &Config,
+) -> Option<Result<String, ScriptError>> {
+    let script = abbrev.script.as_ref()?;
+    let timeout = abbrev.timeout.unwrap_or(config.timeout);
+
+    call::<String>(script, "snippet", command, cwd, timeout).transpose()
+}
+
+/// Returns whether the script of `abbrev` generates its snippet.
+pub fn defines_snippet(abbrev: &Abbrev) -> bool {
+    abbrev
+        .script
+        .as_ref()
+        .is_some_and(|script| defines_function(script, "snippet"))
+}
+
+pub fn print_error(abbrev: &Abbrev, err: &ScriptError) {
+    let error_message = format!("script in abbrev '{}' failed: {}", abbrev.name(), err);
+    let error_style = Color::Red.normal();
+
+    eprintln!("{}", error_style.paint(error_message));
+}
+
+#[cfg(not(feature = "script"))]
+fn call<T>(
+    _script: &str,
+    _function: &str,
+    _command: &str,
+    _cwd: &Path,
+    _timeout: Duration,
+) -> Result<Option<T>, ScriptError> {
+    Err(ScriptError::Unsupported)
+}
+
+#[cfg(not(feature = "script"))]
+fn defines_function(_script: &str, _function: &str) -> bool {
+    // Reported as unsupported when the snippet is generated
+    true
+}
+
+#[cfg(feature = "script")]
+use engine::{call, defines_function};
+
+#[cfg(feature = "script")]
+mod engine {
+    use super::ScriptError;
+    use crate::expand::git::Repository;
+    use rhai::module_resolvers::DummyModuleResolver;
+    use rhai::{Array, CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Scope};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant};
+
+    static MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+    /// Calls `function` of `script` with the context of `command` run in `cwd`, or returns `None`
+    /// if the script does not define it.
+    pub fn call<T: Clone + 'static>(
+        script: &str,
+        function: &str,
+        command: &str,
+        cwd: &Path,
+        timeout: Duration,
+    ) -> Result<Option<T>, ScriptError> {
+        let engine = sandboxed_engine(cwd, timeout);
+        let ast = engine
+            .compile(script)
+            .map_err(|err| ScriptError::Compile(err.to_string()))?;
+
+        if !ast.iter_functions().any(|f| f.name == function) {
+            return Ok(None);
+        }
+
+        let options = CallFnOptions::new().eval_ast(false);
+        let ctx = context(command, cwd);
+
+        engine
+            .call_fn_with_options(options, &mut Scope::new(), &ast, function, (ctx,))
+            .map(Some)
+            .map_err(|err| match *err {
+                EvalAltResult::ErrorTerminated(..) => {
+                    ScriptError::Timeout(function.to_string(), timeout.as_millis())
+                }
+                err => ScriptError::Runtime(function.to_string(), err.to_string()),
+            })
+    }
+
+    pub fn defines_function(script: &str, function: &str) -> bool {
+        Engine::new_raw()
+            .compile(script)
+            .is_ok_and(|ast| ast.iter_functions().any(|f| f.name == function))
+    }
+
+    /// Returns an engine which can only read files under the repository or the current directory,
+    /// and which stops the script after `timeout`.
+    fn sandboxed_engine(cwd: &Path, timeout: Duration) -> Engine {
+        let mut engine = Engine::new();
+        let root = Repository::discover(cwd).map_or(cwd.to_path_buf(), |r| r.root().to_path_buf());
+        let sandbox = Sandbox {
+            cwd: cwd.to_path_buf(),
+            root: root.canonicalize().unwrap_or(root),
+        };
+
+        engine
+            .set_module_resolver(DummyModuleResolver::new())
+            .disable_symbol("eval")
+            .set_max_call_levels(64)
+            .set_max_string_size(MAX_FILE_SIZE as usize)
+            .set_max_array_size(10_000)
+            .set_max_map_size(10_000);
+
+        // zsh evaluates what zabbrev writes to stdout, so the output of scripts goes to stderr
+        engine
+            .on_print(|text| eprintln!("{}", text))
+            .on_debug(|text, _, _| eprintln!("{}", text));
+
+        let started = Instant::now();
+        engine.on_progress(move |_| {
+            if started.elapsed() > timeout {
+                Some(Dynamic::UNIT)
+            } else {
+                None
+            }
+        });
+
+        let s = sandbox.clone();
+        engine.register_fn("exists", move |path: &str| s.resolve(path).is_ok());
+
+        let s = sandbox.clone();
+        engine.register_fn(
+            "read_file",
+            move |path: &str| -> Result<String, Box<EvalAltResult>> {
+                let path = s.resolve(path)?;
+                let size = fs::metadata(&path).map_err(|e| e.to_string())?.len();
+                if size > MAX_FILE_SIZE {
+                    return Err(format!("`{}' is too large", path.display()).into());
+                }
+                fs::read_to_string(&path).map_err(|e| e.to_string().into())
+            },
+        );
+
+        let s = sandbox;
+        engine.register_fn(
+            "read_dir",
+            move |path: &str| -> Result<Array, Box<EvalAltResult>> {
+                let mut names = fs::read_dir(s.resolve(path)?)
+                    .map_err(|e| e.to_string())?
+                    .filter_map(|entry| {
+                        Some(entry.ok()?.file_name().to_string_lossy().into_owned())
+                    })
+                    .collect::<Vec<_>>();
+                names.sort();
+                Ok(names.into_iter().map(Dynamic::from).collect())
+            },
+        );
+
+        engine
+    }
+
+    #[derive(Clone)]
+    struct Sandbox {
+        cwd: PathBuf,
+        root: PathBuf,
+    }
+
+    impl Sandbox {
+        /// Resolves `path` relative to the current directory, following symbolic links.
+        fn resolve(&self, path: &str) -> Result<PathBuf, String> {
+            let resolved = self
+                .cwd
+                .join(path)
+                .canonicalize()
+                .map_err(|e| format!("`{}': {}", path, e))?;
+
+            if resolved.starts_with(&self.root) {
+                Ok(resolved)
+            } else {
+                Err(format!(
+                    "`{}' is outside of `{}'",
+                    path,
+                    self.root.display()
+                ))
+            }
+        }
+    }
+
+    /// Returns the `ctx` given to script functions.
+    fn context(command: &str, cwd: &Path) -> Map {
+        let words = command
+            .split_whitespace()
+            .map(|word| Dynamic::from(word.to_string()))
+            .collect::<Array>();
+        let env = std::env::vars()
+            .map(|(key, value)| (key.into(), Dynamic::from(value)))
+            .collect::<Map>();
+
+        let mut ctx = Map::new();
+        ctx.insert("command".into(), Dynamic::from(command.to_string()));
+        ctx.insert("words".into(), Dynamic::from(words));
+        ctx.insert(
+            "cwd".into(),
+            Dynamic::from(cwd.to_string_lossy().into_owned()),
+        );
+        ctx.insert("env".into(), Dynamic::from(env));
+        ctx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expand::template::current_dir;
+
+    fn test_abbrev(script: &str) -> Abbrev {
+        let mut abbrev: Abbrev = serde_yaml::from_str("{ name: t, abbr: t }").unwrap();
+        abbrev.script = Some(script.to_string());
+        abbrev
+    }
+
+    #[cfg(not(feature = "script"))]
+    #[test]
+    fn test_unsupported() {
+        let config = Config::load_from_str("abbrevs: []").unwrap();
+        let abbrev = test_abbrev("fn matches(ctx) { true }");
+
+        assert!(!is_match(&abbrev, "t", &current_dir(), &config));
+        assert_eq!(
+            generate_snippet(&abbrev, "t", &current_dir(), &config),
+            Some(Err(ScriptError::Unsupported))
+        );
+    }
+
+    #[cfg(feature = "script")]
+    #[test]
+    fn test_script() {
+        let config = Config::load_from_str("abbrevs: []\ntimeout: 100ms").unwrap();
+
+        let abbrev = test_abbrev(
+            r#"
+            fn matches(ctx) { ctx.words.len() == 2 && exists(".") }
+            fn snippet(ctx) { `cargo ${ctx.words[0]}` }
+            "#,
+        );
+        assert!(is_match(&abbrev, "test t", &current_dir(), &config));
+        assert!(!is_match(&abbrev, "t", &current_dir(), &config));
+        assert!(defines_snippet(&abbrev));
+        assert_eq!(
+            generate_snippet(&abbrev, "test t", &current_dir(), &config),
+            Some(Ok("cargo test".to_string()))
+        );
+
+        // Without `matches`, the other conditions decide
+        let abbrev = test_abbrev(r#"fn snippet(ctx) { read_file("/etc/hostname") }"#);
+        assert!(is_match(&abbrev, "t", &current_dir(), &config));
+        assert!(matches!(
+            generate_snippet(&abbrev, "t", &current_dir(), &config),
+            Some(Err(ScriptError::Runtime(_, message))) if message.contains("outside")
+        ));
+
+        let abbrev = test_abbrev("fn snippet(ctx) { loop {} }");
+        assert_eq!(
+            generate_snippet(&abbrev, "t", &current_dir(), &config),
+            Some(Err(ScriptError::Timeout("snippet".to_string(), 100)))
+        );
+
+        let abbrev = test_abbrev("fn matches(ctx) { true }");
+        assert!(!defines_snippet(&abbrev));
+        assert_eq!(
+            generate_snippet(&abbrev, "t", &current_dir(), &config),
+            None
+        );
+    }
+
+    #[cfg(feature = "script")]
+    #[test]
+    fn test_script_output() {
+        // Runs the test below in another process to see what reaches its stdout
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "expand::script::tests::print_from_script"])
+            .args(["--ignored", "--nocapture"])
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        assert!(output.status.success(), "{}", stderr);
+        assert!(stdout.contains("1 passed"), "{}", stdout);
+        assert!(!stdout.contains("printed by"), "{}", stdout);
+        assert!(stderr.contains("printed by print"), "{}", stderr);
+        assert!(stderr.contains("printed by debug"), "{}", stderr);
+    }
+
+    #[cfg(feature = "script")]
+    #[test]
+    #[ignore = "run by test_script_output"]
+    fn print_from_script() {
+        let config = Config::load_from_str("abbrevs: []").unwrap();
+        let abbrev = test_abbrev(
+            r#"fn snippet(ctx) { print("printed by print"); debug("printed by debug"); "ok" }"#,
+        );
+
+        assert_eq!(
+            generate_snippet(&abbrev, "t", &current_dir(), &config),
+            Some(Ok("ok".to_string()))
+        );
+    }
+}
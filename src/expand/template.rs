@@ -0,0 +1,441 @@
+use super::git::Repository;
+use super::line::split_commands;
+use super::recursive::escape_for_evaluation;
+use chrono::format::{Item, StrftimeItems};
+use chrono::Local;
+use regex::{Captures, Regex};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum TemplateError {
+    #[error("unknown variable `{0}'")]
+    UnknownVariable(String),
+
+    #[error("unknown filter `{0}'")]
+    UnknownFilter(String),
+
+    #[error("wrong number of arguments to `{0}'")]
+    WrongArguments(String),
+
+    #[error("invalid date format `{0}'")]
+    InvalidDateFormat(String),
+
+    #[error("unterminated string in `{0}'")]
+    UnterminatedString(String),
+
+    #[error("not in a git repository")]
+    NotInRepository,
+}
+
+/// Shell state passed to templates by the init script.
+#[derive(Debug, Default)]
+pub struct TemplateContext<'a> {
+    /// The previous command in the history
+    pub prev: &'a str,
+    /// The current directory of the shell, also used by scripts and caches
+    pub cwd: PathBuf,
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Pipe,
+}
+
+/// Renders the `{{variable | filter ...}}` expressions in `snippet`.
+///
+/// Cursor placeholders and prompts are left as they are. Values put into a snippet to be
+/// evaluated are escaped, as branch names or the previous command may contain `$(...)`.
+pub fn render_template<'a>(
+    snippet: Cow<'a, str>,
+    context: &TemplateContext,
+    evaluate: bool,
+) -> Result<Cow<'a, str>, TemplateError> {
+    let pattern = Regex::new(r"\{\{([^{}]+)\}\}").unwrap();
+
+    if !pattern.is_match(&snippet) {
+        return Ok(snippet);
+    }
+
+    let mut error = None;
+    let rendered = pattern.replace_all(&snippet, |captures: &Captures| {
+        let expression = &captures[1];
+
+        if expression.trim().is_empty()
+            || expression.starts_with("input:")
+            || expression.starts_with("choice:")
+        {
+            return captures[0].to_string();
+        }
+
+        match evaluate_expression(expression, context) {
+            Ok(value) if evaluate => escape_for_evaluation(&value),
+            Ok(value) => value,
+            // Templates of other tools, like `{{.Names}}` of docker, are left as they are
+            Err(TemplateError::UnknownVariable(name)) if !is_namespaced(&name) => {
+                captures[0].to_string()
+            }
+            Err(err) => {
+                error.get_or_insert(err);
+                String::new()
+            }
+        }
+    });
+
+    match error {
+        Some(err) => Err(err),
+        None => Ok(Cow::from(rendered.into_owned())),
+    }
+}
+
+/// Returns whether `snippet` uses git variables, which are only defined in repositories.
+pub fn requires_repository(snippet: &str) -> bool {
+    let pattern = Regex::new(r"\{\{\s*git\.").unwrap();
+    pattern.is_match(snippet)
+}
+
+/// Returns whether `name` belongs to the variables of zabbrev, such as `git.branch`.
+fn is_namespaced(name: &str) -> bool {
+    ["env.", "prev.", "git.", "cwd."]
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+}
+
+fn evaluate_expression(
+    expression: &str,
+    context: &TemplateContext,
+) -> Result<String, TemplateError> {
+    let tokens = tokenize(expression)?;
+    let mut segments = tokens.split(|token| *token == Token::Pipe);
+
+    let (name, args) = command(segments.next().unwrap_or(&[]), expression)?;
+    let mut value = variable(&name, &args, context)?;
+
+    for segment in segments {
+        let (name, args) = command(segment, expression)?;
+        value = filter(&name, &args, value)?;
+    }
+
+    Ok(value)
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, TemplateError> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '|' => tokens.push(Token::Pipe),
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => text.extend(chars.next()),
+                        Some(c) => text.push(c),
+                        None => {
+                            return Err(TemplateError::UnterminatedString(expression.to_string()))
+                        }
+                    }
+                }
+                tokens.push(Token::Str(text));
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '|' || c == '"' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Splits a segment of the pipeline into its name and string arguments.
+fn command(tokens: &[Token], expression: &str) -> Result<(String, Vec<String>), TemplateError> {
+    let (name, args) = match tokens.split_first() {
+        Some((Token::Word(name), args)) => (name, args),
+        _ => {
+            return Err(TemplateError::UnknownVariable(
+                expression.trim().to_string(),
+            ))
+        }
+    };
+
+    let args = args
+        .iter()
+        .map(|arg| match arg {
+            Token::Str(text) | Token::Word(text) => text.clone(),
+            Token::Pipe => unreachable!(),
+        })
+        .collect();
+
+    Ok((name.clone(), args))
+}
+
+fn variable(
+    name: &str,
+    args: &[String],
+    context: &TemplateContext,
+) -> Result<String, TemplateError> {
+    let no_args = |value: String| {
+        if args.is_empty() {
+            Ok(value)
+        } else {
+            Err(TemplateError::WrongArguments(name.to_string()))
+        }
+    };
+
+    if let Some(key) = name.strip_prefix("env.") {
+        return no_args(std::env::var(key).unwrap_or_default());
+    }
+
+    if let Some(key) = name.strip_prefix("prev.") {
+        let (command, args, last) = split_previous(context.prev);
+
+        return match key {
+            "command" => no_args(command.to_string()),
+            "args" => no_args(args.to_string()),
+            "last" => no_args(last.to_string()),
+            _ => Err(TemplateError::UnknownVariable(name.to_string())),
+        };
+    }
+
+    if let Some(key) = name.strip_prefix("git.") {
+        let repository =
+            Repository::discover(&context.cwd).ok_or(TemplateError::NotInRepository)?;
+
+        return match key {
+            "branch" => no_args(repository.branch()),
+            "root" => no_args(repository.root().to_string_lossy().into_owned()),
+            "upstream" => no_args(repository.upstream()),
+            "remote" => no_args(repository.remote()),
+            "default-branch" => no_args(repository.default_branch()),
+            _ => Err(TemplateError::UnknownVariable(name.to_string())),
+        };
+    }
+
+    match name {
+        "cwd" => no_args(context.cwd.to_string_lossy().into_owned()),
+        "cwd.basename" => no_args(
+            context
+                .cwd
+                .file_name()
+                .map_or(String::new(), |name| name.to_string_lossy().into_owned()),
+        ),
+        "uuid" => no_args(uuid::Uuid::new_v4().to_string()),
+        "date" => match args {
+            [format] => format_date(format),
+            [] => format_date("%Y-%m-%d"),
+            _ => Err(TemplateError::WrongArguments(name.to_string())),
+        },
+        _ => Err(TemplateError::UnknownVariable(name.to_string())),
+    }
+}
+
+fn filter(name: &str, args: &[String], value: String) -> Result<String, TemplateError> {
+    match (name, args) {
+        ("upper", []) => Ok(value.to_uppercase()),
+        ("lower", []) => Ok(value.to_lowercase()),
+        ("quote", []) => Ok(escape(Cow::from(value)).into_owned()),
+        ("default", [default]) if value.is_empty() => Ok(default.clone()),
+        ("default", [_]) => Ok(value),
+        ("upper", _) | ("lower", _) | ("quote", _) | ("default", _) => {
+            Err(TemplateError::WrongArguments(name.to_string()))
+        }
+        _ => Err(TemplateError::UnknownFilter(name.to_string())),
+    }
+}
+
+/// Returns the whole previous command, the arguments and the last word of its last command,
+/// like `!!`, `!*` and `!$`.
+fn split_previous(prev: &str) -> (&str, &str, &str) {
+    let words = split_commands(prev)
+        .into_iter()
+        .rev()
+        .find(|words| !words.is_empty())
+        .unwrap_or_default();
+
+    let args = match (words.get(1), words.last()) {
+        (Some(&(start, _)), Some(&(_, end))) => &prev[start..end],
+        _ => "",
+    };
+    let last = words.last().map_or("", |&(start, end)| &prev[start..end]);
+
+    (prev.trim(), args, last)
+}
+
+/// Returns `$PWD` of the shell, which keeps symbolic links unlike the resolved directory.
+pub fn current_dir() -> PathBuf {
+    std::env::var_os("PWD")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| std::env::current_dir().ok())
+        .unwrap_or_else(|| Path::new("/").to_path_buf())
+}
+
+fn format_date(format: &str) -> Result<String, TemplateError> {
+    let items = StrftimeItems::new(format).collect::<Vec<_>>();
+
+    if items.iter().any(|item| matches!(item, Item::Error)) {
+        return Err(TemplateError::InvalidDateFormat(format.to_string()));
+    }
+
+    Ok(Local::now()
+        .format_with_items(items.into_iter())
+        .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_template() {
+        std::env::set_var("ZABBREV_TEST_NAME", "zabbrev");
+        std::env::remove_var("ZABBREV_TEST_UNSET");
+
+        struct Scenario {
+            pub testname: &'static str,
+            pub snippet: &'static str,
+            pub expected: Result<&'static str, TemplateError>,
+        }
+
+        let scenarios = &[
+            Scenario {
+                testname: "no template",
+                snippet: "git commit -m '{{}}'",
+                expected: Ok("git commit -m '{{}}'"),
+            },
+            Scenario {
+                testname: "environment variable with filters",
+                snippet: "echo {{ env.ZABBREV_TEST_NAME | upper }}",
+                expected: Ok("echo ZABBREV"),
+            },
+            Scenario {
+                testname: "default",
+                snippet: r#"echo {{env.ZABBREV_TEST_UNSET | default "it's" | quote}}"#,
+                expected: Ok(r"echo 'it'\''s'"),
+            },
+            Scenario {
+                testname: "prompts are kept",
+                snippet: "{{input:Name}} {{choice:a|b}}",
+                expected: Ok("{{input:Name}} {{choice:a|b}}"),
+            },
+            Scenario {
+                testname: "go templates are kept",
+                snippet: "docker ps --format '{{.Names}}' && docker inspect -f '{{json .Config}}'",
+                expected: Ok(
+                    "docker ps --format '{{.Names}}' && docker inspect -f '{{json .Config}}'",
+                ),
+            },
+            Scenario {
+                testname: "unknown variable is kept",
+                snippet: "{{home}}",
+                expected: Ok("{{home}}"),
+            },
+            Scenario {
+                testname: "unknown variable of zabbrev",
+                snippet: "{{prev.first}}",
+                expected: Err(TemplateError::UnknownVariable("prev.first".to_string())),
+            },
+            Scenario {
+                testname: "unknown filter",
+                snippet: "{{cwd | reverse}}",
+                expected: Err(TemplateError::UnknownFilter("reverse".to_string())),
+            },
+            Scenario {
+                testname: "unterminated string",
+                snippet: r#"{{date "%Y}}"#,
+                expected: Err(TemplateError::UnterminatedString(r#"date "%Y"#.to_string())),
+            },
+        ];
+
+        for s in scenarios {
+            let actual = render_template(Cow::from(s.snippet), &TemplateContext::default(), false);
+
+            assert_eq!(actual.as_deref(), s.expected.as_deref(), "{}", s.testname);
+        }
+    }
+
+    #[test]
+    fn test_render_template_previous_command() {
+        let context = TemplateContext {
+            prev: "cd /tmp && apt install  -y vim ",
+            ..TemplateContext::default()
+        };
+        let snippet = "{{prev.command}}|{{prev.args}}|{{prev.last}}";
+
+        assert_eq!(
+            render_template(Cow::from(snippet), &context, false).unwrap(),
+            "cd /tmp && apt install  -y vim|install  -y vim|vim"
+        );
+        assert_eq!(
+            render_template(Cow::from(snippet), &TemplateContext::default(), false).unwrap(),
+            "||"
+        );
+    }
+
+    #[test]
+    fn test_render_template_evaluated() {
+        let context = TemplateContext {
+            prev: r"echo $(id) `id` \$HOME",
+            cwd: PathBuf::from("/tmp/$(id)"),
+        };
+        let snippet = "{{prev.command}} {{cwd}} $PWD";
+
+        // Only the values are escaped, so that `(e)` expands the snippet but not them
+        assert_eq!(
+            render_template(Cow::from(snippet), &context, true).unwrap(),
+            r"echo \$(id) \`id\` \\\$HOME /tmp/\$(id) $PWD"
+        );
+    }
+
+    #[test]
+    fn test_render_template_cwd() {
+        let context = TemplateContext {
+            cwd: PathBuf::from("/tmp/project"),
+            ..TemplateContext::default()
+        };
+
+        // The directory given by the shell is used rather than the one of the process
+        assert_eq!(
+            render_template(Cow::from("{{cwd}} {{cwd.basename}}"), &context, false).unwrap(),
+            "/tmp/project project"
+        );
+    }
+
+    #[test]
+    fn test_render_template_generated_values() {
+        let uuid =
+            render_template(Cow::from("{{uuid}}"), &TemplateContext::default(), false).unwrap();
+        let year = render_template(
+            Cow::from(r#"{{date "%Y"}}"#),
+            &TemplateContext::default(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(year, Local::now().format("%Y").to_string());
+        assert_eq!(
+            render_template(
+                Cow::from(r#"{{date "%Q"}}"#),
+                &TemplateContext::default(),
+                false
+            ),
+            Err(TemplateError::InvalidDateFormat("%Q".to_string()))
+        );
+    }
+}
@@ -2,6 +2,7 @@ use crate::opt::InitArgs;
 
 static INIT_SCRIPT: &str = include_str!("zabbrev-init.zsh");
 static BIND_KEYS_SCRIPT: &str = include_str!("zabbrev-bindkey.zsh");
+static BIND_EXPAND_LINE_SCRIPT: &str = include_str!("zabbrev-bindkey-expand-line.zsh");
 
 pub fn run(args: &InitArgs) {
     print!("{}", INIT_SCRIPT);
@@ -9,4 +10,8 @@ pub fn run(args: &InitArgs) {
     if args.bind_keys {
         print!("{}", BIND_KEYS_SCRIPT);
     }
+
+    if args.expand_line {
+        print!("{}", BIND_EXPAND_LINE_SCRIPT);
+    }
 }
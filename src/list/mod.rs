@@ -1,12 +1,15 @@
 use crate::config::abbrev::Trigger;
 use crate::config::Config;
+use crate::expand::template::current_dir;
 use crate::opt::ListArgs;
 use shell_escape::escape;
 use std::borrow::Cow;
 use std::io;
 
 pub fn run(args: &ListArgs) {
-    list(args, &Config::load_or_exit(), &mut io::stdout()).unwrap();
+    let cwd = args.cwd.clone().unwrap_or_else(current_dir);
+
+    list(args, &Config::load_or_exit(&cwd), &mut io::stdout()).unwrap();
 }
 
 fn list<W: io::Write>(_args: &ListArgs, config: &Config, out: &mut W) -> Result<(), io::Error> {
@@ -57,7 +60,7 @@ mod tests {
 
     #[test]
     fn test_list() {
-        let args = ListArgs {};
+        let args = ListArgs { cwd: None };
         let config = test_config();
 
         let mut buf = Vec::new();
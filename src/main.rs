@@ -1,8 +1,12 @@
+mod cache;
 mod config;
 mod expand;
 mod init;
 mod list;
 mod opt;
+#[cfg(test)]
+mod testing;
+mod trust;
 
 use opt::{Opt, Subcommand};
 
@@ -13,5 +17,11 @@ fn main() {
         Subcommand::Init(args) => init::run(args),
         Subcommand::List(args) => list::run(args),
         Subcommand::Expand(args) => expand::run(args),
+        Subcommand::Render(args) => expand::render(args),
+        Subcommand::ExpandLine(args) => expand::line::run(args),
+        Subcommand::Cache(args) => cache::run(args),
+        Subcommand::Allow(args) => trust::allow(args),
+        Subcommand::Deny(args) => trust::deny(args),
+        Subcommand::Trusted(args) => trust::trusted(args),
     }
 }
